@@ -2251,7 +2251,13 @@ impl Catalog {
         source_persist_details: Option<SerializedSourcePersistDetails>,
     ) -> Result<CatalogItem, anyhow::Error> {
         let stmt = sql::parse::parse(&create_sql)?.into_element();
-        let plan = sql::plan::plan(pcx, &self.for_system_session(), stmt, &Params::empty())?;
+        let plan = sql::plan::plan(
+            pcx,
+            &self.for_system_session(),
+            stmt,
+            &Params::empty(),
+            None,
+        )?;
         Ok(match plan {
             Plan::CreateTable(CreateTablePlan { table, .. }) => {
                 assert!(
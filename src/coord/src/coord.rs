@@ -117,7 +117,7 @@ use dataflow_types::{
 use dataflow_types::{SinkAsOf, Timeline};
 use expr::{
     ExprHumanizer, GlobalId, Id, MirRelationExpr, MirScalarExpr, NullaryFunc,
-    OptimizedMirRelationExpr, RowSetFinishing,
+    OptimizedMirRelationExpr, OptimizerNotice, RowSetFinishing,
 };
 use ore::metrics::MetricsRegistry;
 use ore::now::{to_datetime, NowFn};
@@ -141,7 +141,7 @@ use sql::plan::{
     MutationKind, Params, PeekPlan, PeekWhen, Plan, ReadThenWritePlan, SendDiffsPlan,
     SetVariablePlan, ShowVariablePlan, Source, TailPlan,
 };
-use sql::plan::{OptimizerConfig, StatementDesc, View};
+use sql::plan::{LoweringCache, OptimizerConfig, StatementDesc, View};
 use transform::Optimizer;
 
 use self::arrangement_state::{ArrangementFrontiers, Frontiers, SinkWrites};
@@ -269,6 +269,10 @@ where
     pub now: NowFn,
 }
 
+/// The maximum number of lowered statements in the coordinator's
+/// [`LoweringCache`].
+const LOWERING_CACHE_CAPACITY: usize = 1024;
+
 /// Glues the external world to the Timely workers.
 pub struct Coordinator<C>
 where
@@ -278,6 +282,11 @@ where
     dataflow_client: C,
     /// Optimizer instance for logical optimization of views.
     view_optimizer: Optimizer,
+    /// Caches the lowering of planned statements, so that it is not repeated
+    /// when the same statement is planned again, e.g., when a prepared
+    /// statement is executed repeatedly. The cache is emptied whenever it
+    /// fills up.
+    lowering_cache: RefCell<LoweringCache>,
     catalog: Catalog,
     /// Maps (global Id of arrangement) -> (frontier information). This tracks the
     /// `upper` and computed `since` of the indexes. The `since` is the time at
@@ -1475,7 +1484,13 @@ where
         params: &sql::plan::Params,
     ) -> Result<sql::plan::Plan, CoordError> {
         let pcx = session.pcx();
-        let plan = sql::plan::plan(Some(&pcx), &self.catalog.for_session(session), stmt, params)?;
+        let plan = sql::plan::plan(
+            Some(&pcx),
+            &self.catalog.for_session(session),
+            stmt,
+            params,
+            Some(&self.lowering_cache),
+        )?;
         Ok(plan)
     }
 
@@ -3400,20 +3415,32 @@ where
                 let decorrelated_plan = decorrelate(&mut timings, raw_plan);
                 self.validate_timeline(decorrelated_plan.global_uses())?;
                 let dataflow = optimize(&mut timings, self, decorrelated_plan)?;
+                let notices = OptimizerNotice::collect(
+                    dataflow.objects_to_build.iter().map(|build| &*build.view),
+                    row_set_finishing.as_ref(),
+                );
                 let catalog = self.catalog.for_session(session);
                 let formatter =
                     dataflow_types::DataflowGraphFormatter::new(&catalog, options.typed);
                 let mut explanation =
                     dataflow_types::Explanation::new_from_dataflow(&dataflow, &catalog, &formatter);
+                if options.typed {
+                    explanation.explain_monotonic_ids(dataflow.monotonic_ids());
+                }
                 if let Some(row_set_finishing) = row_set_finishing {
                     explanation.explain_row_set_finishing(row_set_finishing);
                 }
+                explanation.explain_notices(notices);
                 explanation.to_string()
             }
             ExplainStage::PhysicalPlan => {
                 let decorrelated_plan = decorrelate(&mut timings, raw_plan);
                 self.validate_timeline(decorrelated_plan.global_uses())?;
                 let dataflow = optimize(&mut timings, self, decorrelated_plan)?;
+                let notices = OptimizerNotice::collect(
+                    dataflow.objects_to_build.iter().map(|build| &*build.view),
+                    row_set_finishing.as_ref(),
+                );
                 let dataflow_plan = dataflow_types::Plan::finalize_dataflow(dataflow)
                     .expect("Dataflow planning failed; unrecoverable error");
                 let catalog = self.catalog.for_session(session);
@@ -3425,6 +3452,7 @@ where
                 if let Some(row_set_finishing) = row_set_finishing {
                     explanation.explain_row_set_finishing(row_set_finishing);
                 }
+                explanation.explain_notices(notices);
                 explanation.to_string()
             }
         };
@@ -4430,6 +4458,7 @@ where
             let mut coord = Coordinator {
                 dataflow_client,
                 view_optimizer: Optimizer::logical_optimizer(),
+                lowering_cache: RefCell::new(LoweringCache::new(LOWERING_CACHE_CAPACITY)),
                 catalog,
                 indexes: ArrangementFrontiers::default(),
                 sources: ArrangementFrontiers::default(),
@@ -28,12 +28,13 @@
 //! printed in contexts where trailing whitespace is unacceptable, like
 //! sqllogictest files.
 
+use std::collections::BTreeSet;
 use std::fmt;
 
 use crate::{DataflowDescription, LinearOperator};
 
 use expr::explain::{Indices, ViewExplanation};
-use expr::{ExprHumanizer, GlobalId, OptimizedMirRelationExpr, RowSetFinishing};
+use expr::{ExprHumanizer, GlobalId, OptimizedMirRelationExpr, OptimizerNotice, RowSetFinishing};
 use ore::result::ResultExt;
 use ore::str::{bracketed, separated};
 
@@ -62,6 +63,10 @@ where
     views: Vec<(GlobalId, &'a ViewExpr)>,
     /// An optional `RowSetFinishing` to mention at the end.
     finishing: Option<RowSetFinishing>,
+    /// The imported sources that were treated as monotonic, if requested.
+    monotonic_ids: Option<BTreeSet<GlobalId>>,
+    /// The notices that the optimizer raised about the plan.
+    notices: BTreeSet<OptimizerNotice>,
 }
 
 impl<'a, Formatter, ViewExpr> Explanation<'a, Formatter, ViewExpr>
@@ -79,6 +84,8 @@ where
             sources: vec![],
             views: vec![(GlobalId::Explain, expr)],
             finishing: None,
+            monotonic_ids: None,
+            notices: BTreeSet::new(),
         }
     }
 
@@ -109,6 +116,8 @@ where
             sources,
             views,
             finishing: None,
+            monotonic_ids: None,
+            notices: BTreeSet::new(),
         }
     }
 
@@ -116,6 +125,24 @@ where
     pub fn explain_row_set_finishing(&mut self, finishing: RowSetFinishing) {
         self.finishing = Some(finishing);
     }
+
+    /// Attach the set of imported sources that were treated as monotonic.
+    pub fn explain_monotonic_ids(&mut self, monotonic_ids: BTreeSet<GlobalId>) {
+        self.monotonic_ids = Some(monotonic_ids);
+    }
+
+    /// Attach the notices that the optimizer raised about the plan.
+    pub fn explain_notices(&mut self, notices: BTreeSet<OptimizerNotice>) {
+        self.notices = notices;
+    }
+
+    /// Returns the name of the object with the given ID, or `?` if it is
+    /// unknown, as for the `Get`s in the views.
+    fn humanize_id(&self, id: GlobalId) -> String {
+        self.expr_humanizer
+            .humanize_id(id)
+            .unwrap_or_else(|| "?".to_owned())
+    }
 }
 
 impl<'a, Formatter, ViewExpr> fmt::Display for Explanation<'a, Formatter, ViewExpr>
@@ -124,14 +151,7 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (id, operator) in &self.sources {
-            writeln!(
-                f,
-                "Source {} ({}):",
-                self.expr_humanizer
-                    .humanize_id(*id)
-                    .unwrap_or_else(|| "?".to_owned()),
-                id,
-            )?;
+            writeln!(f, "Source {} ({}):", self.humanize_id(*id), id)?;
             self.formatter.fmt_source_body(f, operator)?;
             writeln!(f)?;
         }
@@ -142,19 +162,29 @@ where
             if self.sources.len() > 0 || self.views.len() > 1 {
                 match id {
                     GlobalId::Explain => writeln!(f, "Query:")?,
-                    _ => writeln!(
-                        f,
-                        "View {} ({}):",
-                        self.expr_humanizer
-                            .humanize_id(*id)
-                            .unwrap_or_else(|| "?".to_owned()),
-                        id
-                    )?,
+                    _ => writeln!(f, "View {} ({}):", self.humanize_id(*id), id)?,
                 }
             }
             self.formatter.fmt_view(f, view)?;
         }
 
+        if let Some(monotonic_ids) = &self.monotonic_ids {
+            if !monotonic_ids.is_empty() {
+                writeln!(
+                    f,
+                    "\nMonotonic inputs: {}",
+                    separated(
+                        ", ",
+                        monotonic_ids.iter().map(|id| format!(
+                            "{} ({})",
+                            self.humanize_id(*id),
+                            id
+                        ))
+                    )
+                )?;
+            }
+        }
+
         if let Some(finishing) = &self.finishing {
             writeln!(
                 f,
@@ -169,6 +199,13 @@ where
             )?;
         }
 
+        if !self.notices.is_empty() {
+            writeln!(f)?;
+            for notice in &self.notices {
+                writeln!(f, "Notice: {}", notice)?;
+            }
+        }
+
         Ok(())
     }
 }
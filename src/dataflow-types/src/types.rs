@@ -13,7 +13,7 @@
 //! on the interface of the dataflow crate, and not its implementation, can
 //! avoid the dependency, as the dataflow crate is very slow to compile.
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
 use std::ops::Add;
 use std::path::PathBuf;
@@ -291,6 +291,26 @@ impl<View> DataflowDescription<View> {
         result.retain(|id| self.dependent_objects.get(id).is_none());
         result
     }
+
+    /// Returns the identifiers of imported sources that are monotonic.
+    ///
+    /// A monotonic (append-only) source never retracts records, which allows
+    /// some operators over it to be rendered more efficiently. Currently only
+    /// external sources without an envelope are known to be monotonic.
+    pub fn monotonic_ids(&self) -> BTreeSet<GlobalId> {
+        self.source_imports
+            .iter()
+            .filter_map(
+                |(source_id, (source_desc, _))| match source_desc.connector {
+                    SourceConnector::External {
+                        envelope: SourceEnvelope::None(_),
+                        ..
+                    } => Some(*source_id),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
 }
 
 /// A description of how to interpret data from various sources
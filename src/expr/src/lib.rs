@@ -20,6 +20,7 @@ use repr::{ColumnType, ScalarType};
 
 mod id;
 mod linear;
+mod notice;
 mod relation;
 mod scalar;
 
@@ -34,6 +35,7 @@ pub use linear::{
     util::permutation_to_map_and_new_arity,
     MapFilterProject,
 };
+pub use notice::OptimizerNotice;
 pub use relation::func::{AggregateFunc, TableFunc};
 pub use relation::func::{AnalyzedRegex, CaptureGroupDesc};
 pub use relation::join_input_mapper::JoinInputMapper;
@@ -0,0 +1,77 @@
+// Copyright Materialize, Inc. and contributors. All rights reserved.
+//
+// Use of this software is governed by the Business Source License
+// included in the LICENSE file.
+//
+// As of the Change Date specified in that file, in accordance with
+// the Business Source License, use of this software will be governed
+// by the Apache License, Version 2.0.
+
+//! Notices that the optimizer raises about a plan.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::{MirRelationExpr, RowSetFinishing};
+
+/// A notice about a plan that does not prevent it from being executed, but
+/// that the user may want to know about, e.g., when explaining the plan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptimizerNotice {
+    /// A `LIMIT` or `OFFSET` is applied without an `ORDER BY`, so the rows
+    /// that it selects may vary between executions of the same query.
+    NondeterministicLimit,
+}
+
+impl OptimizerNotice {
+    /// Collects the notices raised by `exprs` and by the `finishing` that is
+    /// applied to their results, if any.
+    ///
+    /// An ungrouped [`MirRelationExpr::TopK`] with a limit or an offset but no
+    /// ordering, including one nested inside a view, raises
+    /// [`OptimizerNotice::NondeterministicLimit`], as does such a finishing.
+    /// Grouped `TopK`s are not flagged, as they also arise from other
+    /// constructs, like `DISTINCT ON`.
+    pub fn collect<'a, I>(
+        exprs: I,
+        finishing: Option<&RowSetFinishing>,
+    ) -> BTreeSet<OptimizerNotice>
+    where
+        I: IntoIterator<Item = &'a MirRelationExpr>,
+    {
+        let mut notices = BTreeSet::new();
+        for expr in exprs {
+            expr.visit_pre(&mut |e| {
+                if let MirRelationExpr::TopK {
+                    group_key,
+                    order_key,
+                    limit,
+                    offset,
+                    ..
+                } = e
+                {
+                    if group_key.is_empty()
+                        && order_key.is_empty()
+                        && (limit.is_some() || *offset > 0)
+                    {
+                        notices.insert(OptimizerNotice::NondeterministicLimit);
+                    }
+                }
+            });
+        }
+        if finishing.map_or(false, |f| f.has_unordered_limit()) {
+            notices.insert(OptimizerNotice::NondeterministicLimit);
+        }
+        notices
+    }
+}
+
+impl fmt::Display for OptimizerNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptimizerNotice::NondeterministicLimit => {
+                f.write_str("LIMIT or OFFSET without ORDER BY returns an arbitrary subset of rows")
+            }
+        }
+    }
+}
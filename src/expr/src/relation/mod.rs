@@ -10,7 +10,7 @@
 #![warn(missing_docs)]
 
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
 use itertools::Itertools;
@@ -1078,6 +1078,34 @@ impl MirRelationExpr {
         explanation.to_string()
     }
 
+    /// Print this MirRelationExpr to a string in a canonical form, suitable
+    /// for comparing plans in tests.
+    ///
+    /// Local identifiers are renumbered in the order in which they are bound,
+    /// so the output does not depend on the identifiers allocated while
+    /// planning. Like [`MirRelationExpr::pretty`], the output contains neither
+    /// types nor human-readable names.
+    pub fn to_canonical_ir(&self) -> String {
+        let mut expr = self.clone();
+        let mut ids = HashMap::new();
+        expr.visit_mut_pre(&mut |e| match e {
+            MirRelationExpr::Let { id, .. } => {
+                let canonical = LocalId::new(ids.len() as u64);
+                ids.insert(*id, canonical);
+                *id = canonical;
+            }
+            MirRelationExpr::Get {
+                id: Id::Local(id), ..
+            } => {
+                if let Some(canonical) = ids.get(id) {
+                    *id = *canonical;
+                }
+            }
+            _ => {}
+        });
+        expr.pretty()
+    }
+
     /// Take ownership of `self`, leaving an empty `MirRelationExpr::Constant` with the correct type.
     pub fn take_safely(&mut self) -> MirRelationExpr {
         let typ = self.typ();
@@ -2118,6 +2146,13 @@ impl RowSetFinishing {
             && self.offset == 0
             && self.project.iter().copied().eq(0..arity)
     }
+    /// True if the finishing applies a limit or offset without an ordering.
+    ///
+    /// SQL does not specify which rows such a finishing selects, so the
+    /// results may vary between executions of the same query.
+    pub fn has_unordered_limit(&self) -> bool {
+        self.order_by.is_empty() && (self.limit.is_some() || self.offset > 0)
+    }
     /// Applies finishing actions to a row set.
     pub fn finish(&self, rows: &mut Vec<Row>) {
         let mut left_datum_vec = repr::DatumVec::new();
@@ -2181,3 +2216,24 @@ where
     }
     tiebreaker()
 }
+
+#[cfg(test)]
+mod tests {
+    use ore::id_gen::IdGen;
+    use repr::RelationType;
+
+    use super::MirRelationExpr;
+
+    #[test]
+    fn test_canonical_ir() {
+        let plan = |id_gen: &mut IdGen| {
+            MirRelationExpr::constant(vec![], RelationType::empty())
+                .let_in(id_gen, |_id_gen, get| get.clone().union(get))
+        };
+        let mut id_gen = IdGen::default();
+        let a = plan(&mut id_gen);
+        let b = plan(&mut id_gen);
+        assert_ne!(a.pretty(), b.pretty());
+        assert_eq!(a.to_canonical_ir(), b.to_canonical_ir());
+    }
+}
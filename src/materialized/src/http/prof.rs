@@ -85,7 +85,7 @@ async fn time_prof<'a>(
         unsafe { prof::time::prof_time(Duration::from_secs(10), 99, merge_threads) }.await?;
     // Fail with a compile error if we weren't holding the jemalloc lock.
     drop(ctl_lock);
-    flamegraph(stacks, "CPU Time Flamegraph", false, &[])
+    flamegraph(stacks, "CPU Time Flamegraph", false, &[], max_depth(params))
 }
 
 fn flamegraph(
@@ -93,8 +93,12 @@ fn flamegraph(
     title: &str,
     display_bytes: bool,
     extras: &[&str],
+    max_depth: Option<usize>,
 ) -> anyhow::Result<Response<Body>> {
-    let collated = prof::collate_stacks(stacks);
+    let collated = match max_depth {
+        Some(max_depth) => stacks.cap_depth_weighted(max_depth),
+        None => prof::collate_stacks(stacks),
+    };
     let data_json = RefCell::new(String::new());
     collated.dfs(
         |node| {
@@ -123,6 +127,12 @@ fn flamegraph(
     }))
 }
 
+/// Returns the maximum flamegraph depth requested by the `max_depth`
+/// parameter, if any.
+fn max_depth(params: &HashMap<Cow<'_, str>, Cow<'_, str>>) -> Option<usize> {
+    params.get("max_depth").and_then(|d| d.parse().ok())
+}
+
 mod disabled {
     use std::collections::HashMap;
 
@@ -173,19 +183,18 @@ mod disabled {
 mod enabled {
     use std::borrow::Cow;
     use std::collections::HashMap;
-    use std::fmt::Write;
     use std::io::{BufReader, Read};
     use std::sync::Arc;
 
     use hyper::http::HeaderValue;
     use hyper::{header, Body, Method, Request, Response, StatusCode};
-    use prof::symbolicate;
     use tokio::sync::Mutex;
     use url::form_urlencoded;
 
-    use prof::jemalloc::{parse_jeheap, JemallocProfCtl, PROF_CTL};
+    use prof::jemalloc::{parse_jeheap, JeheapOptions, JemallocProfCtl, PROF_CTL};
+    use prof::StackProfile;
 
-    use super::{flamegraph, time_prof, MemProfilingStatus, ProfTemplate};
+    use super::{flamegraph, max_depth, time_prof, MemProfilingStatus, ProfTemplate};
     use crate::http::util;
     use crate::BUILD_INFO;
 
@@ -222,6 +231,15 @@ mod enabled {
         prof_ctl: &Arc<Mutex<JemallocProfCtl>>,
     ) -> Result<Response<Body>, anyhow::Error> {
         let query = body.uri().query().map(str::to_string);
+        let accepts_gzip = body
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|encodings| encodings.to_str().ok())
+            .map_or(false, |encodings| {
+                encodings
+                    .split(',')
+                    .any(|encoding| encoding.split(';').next().unwrap_or("").trim() == "gzip")
+            });
         let body = hyper::body::to_bytes(body).await?;
         let params: HashMap<_, _> = form_urlencoded::parse(&body).collect();
         let action = match params.get("action") {
@@ -266,43 +284,47 @@ mod enabled {
                 let mut borrow = prof_ctl.lock().await;
                 let f = borrow.dump()?;
                 let r = BufReader::new(f);
-                let stacks = parse_jeheap(r)?;
-                let syms = symbolicate(&stacks);
-                let mut s = String::new();
-                // Emitting the format expected by Brendan Gregg's flamegraph tool.
-                //
-                // foo;bar;quux 30
-                // foo;bar;asdf 40
-                //
-                // etc.
-                for (stack, _anno) in stacks.iter() {
-                    for (i, addr) in stack.addrs.iter().enumerate() {
-                        let syms = match syms.get(addr) {
-                            Some(syms) => Cow::Borrowed(syms),
-                            None => Cow::Owned(vec!["???".to_string()]),
-                        };
-                        for (j, sym) in syms.iter().enumerate() {
-                            if j != 0 || i != 0 {
-                                s.push_str(";");
-                            }
-                            s.push_str(&*sym);
-                        }
+                // The format may be requested in the form or the query string.
+                let query_params: HashMap<_, _> =
+                    form_urlencoded::parse(query.as_deref().unwrap_or("").as_bytes()).collect();
+                let format = match params.get("format").or_else(|| query_params.get("format")) {
+                    Some(name) => {
+                        ProfileFormat::from_name(name).map(|format| (format, format.content_type()))
                     }
-                    writeln!(&mut s, " {}", stack.weight).unwrap();
-                }
-                Ok(Response::builder()
+                    None => ProfileFormat::from_accept(accept.as_ref()),
+                };
+                let (format, content_type) = match format {
+                    Some(format) => format,
+                    None => {
+                        return Ok(util::error_response(
+                            StatusCode::NOT_ACCEPTABLE,
+                            "unsupported profile format; expected one of: \
+                             mzfg, collapsed, pprof, speedscope",
+                        ))
+                    }
+                };
+                let stacks = parse_jeheap(r, jeheap_options(&params))?;
+                let mut response = Response::builder()
+                    .header(header::CONTENT_TYPE, content_type)
                     .header(
                         header::CONTENT_DISPOSITION,
-                        "attachment; filename=\"mz.fg\"",
-                    )
-                    .body(Body::from(s))
-                    .unwrap())
+                        format!("attachment; filename=\"{}\"", format.filename()),
+                    );
+                // `mzfg` files are large but compress well.
+                let body = match format {
+                    ProfileFormat::Mzfg if accepts_gzip => {
+                        response = response.header(header::CONTENT_ENCODING, "gzip");
+                        Body::from(stacks.to_mzfg_gzip(true, &[])?)
+                    }
+                    _ => format.render(&stacks),
+                };
+                Ok(response.body(body).unwrap())
             }
             "mem_fg" => {
                 let mut borrow = prof_ctl.lock().await;
                 let f = borrow.dump()?;
                 let r = BufReader::new(f);
-                let stacks = parse_jeheap(r)?;
+                let stacks = parse_jeheap(r, jeheap_options(&params))?;
                 let stats = borrow.stats()?;
                 let stats_rendered = &[
                     format!("Allocated: {}", HumanFormattedBytes(stats.allocated)),
@@ -322,7 +344,13 @@ mod enabled {
                     .iter()
                     .map(String::as_str)
                     .collect::<Vec<_>>();
-                flamegraph(stacks, "Heap Flamegraph", true, &stats_rendered)
+                flamegraph(
+                    stacks,
+                    "Heap Flamegraph",
+                    true,
+                    &stats_rendered,
+                    max_depth(&params),
+                )
             }
             "time_fg" => time_prof(&params).await,
             x => Ok(util::error_response(
@@ -332,11 +360,148 @@ mod enabled {
         }
     }
 
+    /// A format in which a heap profile can be downloaded.
+    #[derive(Clone, Copy, Debug)]
+    enum ProfileFormat {
+        Mzfg,
+        Collapsed,
+        Pprof,
+        Speedscope,
+    }
+
+    impl ProfileFormat {
+        /// Returns the format named by a `format` parameter.
+        fn from_name(name: &str) -> Option<ProfileFormat> {
+            match name {
+                "mzfg" => Some(ProfileFormat::Mzfg),
+                "collapsed" => Some(ProfileFormat::Collapsed),
+                "pprof" => Some(ProfileFormat::Pprof),
+                "speedscope" => Some(ProfileFormat::Speedscope),
+                _ => None,
+            }
+        }
+
+        /// Returns the format most preferred by an `Accept` header, and the
+        /// media type to serve it as.
+        ///
+        /// Media ranges are preferred by their `q` parameter and then by their
+        /// order in the header, and those with `q=0` are never chosen. Without
+        /// an `Accept` header, profiles are served in the collapsed format.
+        fn from_accept(accept: Option<&HeaderValue>) -> Option<(ProfileFormat, &'static str)> {
+            let accept = match accept {
+                Some(accept) => accept.to_str().ok()?,
+                None => {
+                    let format = ProfileFormat::Collapsed;
+                    return Some((format, format.content_type()));
+                }
+            };
+            let mut best: Option<(f64, ProfileFormat, &'static str)> = None;
+            for media_range in accept.split(',') {
+                let mut parts = media_range.split(';');
+                let media_type = parts.next().unwrap_or("").trim();
+                let q = parts
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                    .map_or(Some(1.0), |(_, q)| q.trim().parse::<f64>().ok());
+                let q = match q {
+                    Some(q) if q > 0.0 => q,
+                    _ => continue,
+                };
+                let (format, content_type) = match media_type {
+                    "application/x-mzfg" => (ProfileFormat::Mzfg, "application/x-mzfg"),
+                    "text/plain" | "text/*" | "*/*" => (ProfileFormat::Collapsed, "text/plain"),
+                    "application/octet-stream" => {
+                        (ProfileFormat::Pprof, "application/octet-stream")
+                    }
+                    "application/x-protobuf" => (ProfileFormat::Pprof, "application/x-protobuf"),
+                    "application/json" => (ProfileFormat::Speedscope, "application/json"),
+                    _ => continue,
+                };
+                if best.map_or(true, |(best_q, _, _)| q > best_q) {
+                    best = Some((q, format, content_type));
+                }
+            }
+            best.map(|(_, format, content_type)| (format, content_type))
+        }
+
+        /// Returns the media type to serve the format as when it is requested
+        /// by name.
+        fn content_type(self) -> &'static str {
+            match self {
+                ProfileFormat::Mzfg => "application/x-mzfg",
+                ProfileFormat::Collapsed => "text/plain",
+                ProfileFormat::Pprof => "application/octet-stream",
+                ProfileFormat::Speedscope => "application/json",
+            }
+        }
+
+        fn filename(self) -> &'static str {
+            match self {
+                ProfileFormat::Mzfg => "mz.mzfg",
+                ProfileFormat::Collapsed => "mz.fg",
+                // The pprof encoding is gzipped.
+                ProfileFormat::Pprof => "mz.pb.gz",
+                ProfileFormat::Speedscope => "mz.speedscope.json",
+            }
+        }
+
+        fn render(self, stacks: &StackProfile) -> Body {
+            match self {
+                ProfileFormat::Mzfg => Body::from(stacks.to_mzfg_unchecked(true, &[])),
+                ProfileFormat::Collapsed => Body::from(stacks.to_collapsed(true)),
+                ProfileFormat::Pprof => Body::from(stacks.to_pprof(("space", "bytes"), None)),
+                ProfileFormat::Speedscope => Body::from(stacks.to_speedscope("Heap profile")),
+            }
+        }
+    }
+
+    fn jeheap_options(params: &HashMap<Cow<'_, str>, Cow<'_, str>>) -> JeheapOptions {
+        JeheapOptions {
+            annotate_size_classes: params.get("size_classes").map(AsRef::as_ref)
+                == Some("annotate"),
+            unbias: params.get("weights").map(AsRef::as_ref) != Some("sampled"),
+        }
+    }
+
+    /// Returns the headline numbers of the current heap profile as JSON,
+    /// including the `top` heaviest leaf functions (10 by default).
+    async fn handle_summary(
+        params: &HashMap<Cow<'_, str>, Cow<'_, str>>,
+        prof_ctl: &Arc<Mutex<JemallocProfCtl>>,
+    ) -> anyhow::Result<Response<Body>> {
+        let top = match params.get("top") {
+            Some(top) => match top.parse() {
+                Ok(top) => top,
+                Err(_) => {
+                    return Ok(util::error_response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid `top` parameter: {}", top),
+                    ))
+                }
+            },
+            None => 10,
+        };
+        let mut borrow = prof_ctl.lock().await;
+        let f = borrow.dump()?;
+        let stacks = parse_jeheap(BufReader::new(f), jeheap_options(params))?;
+        let summary = serde_json::to_string(&stacks.summary(top))?;
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(summary))
+            .unwrap())
+    }
+
     pub async fn handle_get(
         query: Option<&str>,
         accept: Option<HeaderValue>,
         prof_ctl: &Arc<Mutex<JemallocProfCtl>>,
     ) -> anyhow::Result<Response<Body>> {
+        if let Some(query) = query {
+            let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes()).collect();
+            if params.get("summary").map(AsRef::as_ref) == Some("true") {
+                return handle_summary(&params, prof_ctl).await;
+            }
+        }
         match query {
             Some("dump_stats") => {
                 let json = accept.map_or(false, |accept| accept.as_bytes() == b"application/json");
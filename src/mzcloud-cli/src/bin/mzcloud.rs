@@ -15,12 +15,30 @@
 
 //! Command-line interface for Materialize Cloud.
 
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::fs;
-use std::io::Cursor;
-use std::process;
+use std::future::Future;
+use std::io::{self, Cursor, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, Context};
+use clap::{AppSettings, IntoApp};
+use clap_complete::Shell;
+use rand::Rng;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time;
+use tracing::{debug, trace};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use zip::ZipArchive;
 
 use mzcloud::apis::configuration::Configuration;
@@ -30,6 +48,7 @@ use mzcloud::apis::deployments_api::{
     deployments_tailscale_logs_retrieve,
 };
 use mzcloud::apis::mz_versions_api::mz_versions_list;
+use mzcloud::models::deployment::Deployment;
 use mzcloud::models::deployment_request::DeploymentRequest;
 use mzcloud::models::deployment_size_enum::DeploymentSizeEnum;
 use mzcloud::models::patched_deployment_update_request::PatchedDeploymentUpdateRequest;
@@ -38,8 +57,24 @@ use mzcloud::models::supported_cloud_region_request::SupportedCloudRegionRequest
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// The port on which deployments accept SQL connections.
+const SQL_PORT: u16 = 6875;
+
+/// The exit codes of the process, as listed at the end of `--help`.
+///
+/// See [`FailureClass::exit_code`].
+const EXIT_CODES: &str = "EXIT CODES:
+    0    Success
+    1    Any other failure
+    2    Invalid command-line arguments
+    3    Authentication failed
+    4    A requested resource does not exist
+    5    The API could not be reached, or a request timed out
+    6    The API rejected a request as invalid";
+
 /// Command-line interface for Materialize Cloud.
 #[derive(Debug, clap::Parser)]
+#[clap(after_help = EXIT_CODES)]
 struct Args {
     #[clap(flatten)]
     oauth: OAuthArgs,
@@ -59,6 +94,20 @@ struct Args {
     #[clap(long, env = "MZCLOUD_INSECURE", hide = true)]
     insecure: Option<bool>,
 
+    /// The cloud provider:region pair in which to create deployments, unless
+    /// overridden by a command. Example: `aws:us-east-1`
+    ///
+    /// The API itself is not regional, so this does not affect other
+    /// commands.
+    #[clap(long, global = true, env = "MZCLOUD_REGION", parse(try_from_str = parse_cloud_region))]
+    region: Option<SupportedCloudRegionRequest>,
+
+    /// The base URL of the core API, e.g. `https://cloud.materialize.com`.
+    ///
+    /// Overrides the URL derived from `domain` and `insecure`.
+    #[clap(long, env = "MZCLOUD_API_URL")]
+    api_url: Option<String>,
+
     /// The domain of the admin API.
     ///
     /// Defaults to `admin.{domain}` unless `domain` is set to `localhost`, in
@@ -68,6 +117,69 @@ struct Args {
     #[clap(long, env = "MZCLOUD_ADMIN_DOMAIN", hide = true)]
     admin_domain: Option<String>,
 
+    /// Page output through `$PAGER` (or `less`), even if it fits on screen.
+    ///
+    /// By default, output is paged only if stdout is a terminal and the output
+    /// is taller than the terminal. Output is never paged if stdout is not a
+    /// terminal.
+    #[clap(long, conflicts_with = "no-pager")]
+    pager: bool,
+
+    /// Never page output.
+    #[clap(long)]
+    no_pager: bool,
+
+    /// The format in which to print command output. Defaults to `json`.
+    #[clap(short, long, arg_enum)]
+    output: Option<OutputFormat>,
+
+    /// Print only the IDs of the resources that a command outputs, one per
+    /// line, rather than the resources themselves.
+    #[clap(short, long, conflicts_with = "output")]
+    quiet: bool,
+
+    /// Log API requests to stderr. Pass twice to also log response bodies.
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u64,
+
+    /// The URL of an HTTP or SOCKS5 proxy through which to make all requests,
+    /// e.g. `socks5://localhost:1080`.
+    ///
+    /// Overrides the `HTTPS_PROXY`, `HTTP_PROXY`, and `ALL_PROXY` environment
+    /// variables. Otherwise, `HTTPS_PROXY` and `HTTP_PROXY` apply to requests
+    /// with the corresponding scheme, and `ALL_PROXY` applies to all requests
+    /// if neither of those is set.
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// The number of seconds to wait to connect to the API before giving up.
+    #[clap(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// The number of seconds to wait for an API request to complete before
+    /// giving up.
+    #[clap(long, default_value = "60")]
+    request_timeout: u64,
+
+    /// Print the requests that commands which change deployments would make,
+    /// without making them or authenticating. Other commands are unaffected.
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// The maximum number of times to retry an API request that fails with a
+    /// transient error.
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+
+    /// The TOML file from which to read defaults for `--client-id`,
+    /// `--secret`, `--api-url`, `--region`, and `--output`.
+    ///
+    /// Defaults to `mzcloud/config.toml` in `$XDG_CONFIG_HOME`, or in
+    /// `~/.config` if that is not set. It is not an error for the default
+    /// file not to exist.
+    #[clap(long, env = "MZCLOUD_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Which resources to operate on.
     #[clap(subcommand)]
     category: Category,
@@ -81,6 +193,9 @@ impl Args {
 
     /// Returns the base URL at which the core API is hosted.
     fn url(&self) -> String {
+        if let Some(api_url) = &self.api_url {
+            return api_url.trim_end_matches('/').to_owned();
+        }
         let insecure = self.insecure.unwrap_or_else(|| self.is_localhost());
         match insecure {
             true => format!("http://{}", self.domain),
@@ -96,18 +211,306 @@ impl Args {
             None => format!("https://admin.{}", self.domain),
         }
     }
+
+    /// Reports whether the command only prints the request that it would make,
+    /// because of `--dry-run`, and so needs no access token.
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+            && matches!(
+                self.category,
+                Category::Deployments(
+                    DeploymentsCommand::Create { .. }
+                        | DeploymentsCommand::Update { .. }
+                        | DeploymentsCommand::Scale { .. }
+                        | DeploymentsCommand::Destroy { .. }
+                )
+            )
+    }
+
+    /// Returns the printer to use for command output.
+    fn printer(&self) -> Printer {
+        let pager = match (self.pager, self.no_pager) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            (false, false) => None,
+        };
+        Printer {
+            pager,
+            output: self.output.clone(),
+            quiet: self.quiet,
+        }
+    }
+
+    /// Fills in the options that were not given on the command line or in
+    /// the environment from `file`.
+    fn apply_config_file(&mut self, file: ConfigFile) -> anyhow::Result<()> {
+        if self.oauth.client_id.is_none() {
+            self.oauth.client_id = file.client_id;
+        }
+        if self.oauth.secret.is_none() {
+            self.oauth.secret = file.secret;
+        }
+        if self.api_url.is_none() {
+            self.api_url = file.api_url;
+        }
+        if let (None, Some(region)) = (&self.region, &file.region) {
+            let region = parse_cloud_region(region)
+                .map_err(|e| anyhow!("Invalid region in config file: {}", e))?;
+            self.region = Some(region);
+        }
+        if self.output.is_none() {
+            self.output = file.output;
+        }
+        Ok(())
+    }
+
+    /// Returns the HTTP client to use for all requests.
+    fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout))
+            .timeout(Duration::from_secs(self.request_timeout));
+        let proxy = self.proxy.clone().or_else(all_proxy_from_env);
+        if let Some(proxy) = proxy {
+            let proxy = reqwest::Proxy::all(&proxy)
+                .with_context(|| format!("invalid proxy URL {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Returns the retrier to use for API requests.
+    fn retrier(&self) -> Retrier {
+        Retrier {
+            max_retries: self.max_retries,
+        }
+    }
+}
+
+/// Returns the proxy URL in `ALL_PROXY`, unless `HTTPS_PROXY` or `HTTP_PROXY`
+/// is set. The HTTP client respects those two itself, but not `ALL_PROXY`.
+fn all_proxy_from_env() -> Option<String> {
+    let var = |name: &str| {
+        env::var(name)
+            .or_else(|_| env::var(name.to_lowercase()))
+            .ok()
+    };
+    if var("HTTPS_PROXY").is_some() || var("HTTP_PROXY").is_some() {
+        return None;
+    }
+    var("ALL_PROXY")
+}
+
+/// A format in which to print command output.
+#[derive(clap::ArgEnum, Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    /// Pretty-printed JSON.
+    Json,
+    /// JSON on a single line.
+    JsonCompact,
+    /// YAML.
+    Yaml,
+    /// An aligned, human-readable table.
+    Table,
+}
+
+/// A column of a table of values, as its header and the dotted path of the
+/// field that it displays (see `flatten_json`).
+type Column = (&'static str, &'static str);
+
+/// The columns of the table of deployments.
+const DEPLOYMENT_COLUMNS: &[Column] = &[
+    ("ID", "id"),
+    ("SIZE", "size"),
+    ("VERSION", "mzVersion"),
+    ("STATUS", "status"),
+];
+
+/// Writes command output to stdout, paging it if appropriate.
+struct Printer {
+    /// Whether to page output. If `None`, output is paged only if it is taller
+    /// than the terminal.
+    pager: Option<bool>,
+    /// The format in which to print values, if one was requested. Values are
+    /// printed as pretty JSON by default.
+    output: Option<OutputFormat>,
+    /// Whether to print only the IDs of values that have them.
+    quiet: bool,
+}
+
+impl Printer {
+    /// Prints a value in the requested output format.
+    ///
+    /// As a table, an object is printed as one row per field, and a list as
+    /// one row per element, with a column for each field of its elements.
+    fn print_value<T: Serialize>(&self, value: &T) -> anyhow::Result<()> {
+        self.print_list(value, &[])
+    }
+
+    /// Like [`Printer::print_value`], but prints a list as a table with the
+    /// given columns, if any.
+    fn print_list<T: Serialize>(&self, value: &T, columns: &[Column]) -> anyhow::Result<()> {
+        if self.quiet {
+            if let Some(ids) = render_ids(&serde_json::to_value(value)?) {
+                return self.print(&ids);
+            }
+        }
+        let out = match self.output.as_ref().unwrap_or(&OutputFormat::Json) {
+            OutputFormat::Json => format!("{}\n", serde_json::to_string_pretty(value)?),
+            OutputFormat::JsonCompact => format!("{}\n", serde_json::to_string(value)?),
+            OutputFormat::Yaml => render_yaml(&serde_json::to_value(value)?),
+            OutputFormat::Table => render_table(serde_json::to_value(value)?, columns),
+        };
+        self.print(&out)
+    }
+
+    /// Prints `text` if no output format was requested, or else `value` in the
+    /// requested format.
+    ///
+    /// This is for commands whose output is meant to be read as text, but
+    /// which scripts may want in a structured format instead.
+    fn print_text_or_value<T: Serialize>(&self, text: &str, value: &T) -> anyhow::Result<()> {
+        match self.output {
+            None => self.print(text),
+            Some(_) => self.print_value(value),
+        }
+    }
+
+    /// Prints `s` verbatim.
+    fn print(&self, s: &str) -> anyhow::Result<()> {
+        if self.should_page(s) && page(s)? {
+            return Ok(());
+        }
+        print!("{}", s);
+        Ok(())
+    }
+
+    fn should_page(&self, s: &str) -> bool {
+        if !atty::is(atty::Stream::Stdout) {
+            return false;
+        }
+        match self.pager {
+            Some(pager) => pager,
+            None => match terminal_size::terminal_size() {
+                Some((_, terminal_size::Height(height))) => s.lines().count() > height.into(),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Pipes `s` through the user's pager.
+///
+/// Like Git, this uses `$PAGER` if set, or `less` otherwise, and sets `LESS=FRX`
+/// if `LESS` is not already set. Returns `false` if the pager could not be
+/// started, in which case the caller should print the output directly.
+fn page(s: &str) -> anyhow::Result<bool> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".into());
+    let mut words = pager.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return Ok(false),
+    };
+    let mut cmd = process::Command::new(program);
+    cmd.args(words).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        cmd.env("LESS", "FRX");
+    }
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(_) => return Ok(false),
+    };
+    // The pager may exit without reading all of its input, e.g. if the user
+    // quits early, so errors writing to it are expected and ignored.
+    let _ = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(s.as_bytes());
+    child.wait()?;
+    Ok(true)
+}
+
+/// Defaults for command-line options, read from a TOML file.
+///
+/// Options given on the command line or in the environment take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    client_id: Option<String>,
+    secret: Option<String>,
+    api_url: Option<String>,
+    region: Option<String>,
+    output: Option<OutputFormat>,
+}
+
+impl ConfigFile {
+    /// Reads the config file at `path`, or, if `path` is `None`, the default
+    /// config file, if it exists.
+    fn load(path: Option<&Path>) -> anyhow::Result<ConfigFile> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == io::ErrorKind::NotFound => {
+                return Ok(ConfigFile::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading config file {}", path.display()))
+            }
+        };
+        toml::from_str(&contents).with_context(|| format!("parsing config file {}", path.display()))
+    }
+}
+
+/// Returns the path of the default config file, if there is a home directory
+/// in which to look for it.
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("mzcloud").join("config.toml"))
 }
 
 #[derive(Debug, clap::Parser, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct OAuthArgs {
-    /// OAuth Client ID for authentication.
+    /// OAuth Client ID for authentication. Required by all commands that
+    /// access the API.
     #[clap(long, env = "MZCLOUD_CLIENT_ID", hide_env_values = true)]
-    client_id: String,
+    client_id: Option<String>,
 
-    /// OAuth Secret Key for authentication.
+    /// OAuth Secret Key for authentication. Required by all commands that
+    /// access the API.
     #[clap(long, env = "MZCLOUD_SECRET_KEY", hide_env_values = true)]
-    secret: String,
+    secret: Option<String>,
+
+    /// A file containing the OAuth Secret Key, as an alternative to
+    /// `--secret`. Surrounding whitespace is ignored.
+    #[clap(long, env = "MZCLOUD_SECRET_FILE")]
+    #[serde(skip)]
+    secret_file: Option<PathBuf>,
+}
+
+impl OAuthArgs {
+    /// Reads the secret key from `secret_file`, if given.
+    fn read_secret_file(&mut self) -> anyhow::Result<()> {
+        if let Some(path) = &self.secret_file {
+            if self.secret.is_some() {
+                bail!("Both a secret key and a secret key file were given. Pass only one of --secret (MZCLOUD_SECRET_KEY) and --secret-file (MZCLOUD_SECRET_FILE).");
+            }
+            let secret = fs::read_to_string(path)
+                .with_context(|| format!("reading secret key file {}", path.display()))?;
+            self.secret = Some(secret.trim().to_owned());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, clap::Parser)]
@@ -118,6 +521,35 @@ enum Category {
     /// List Materialize versions.
     #[clap(subcommand)]
     MzVersions(MzVersionsCommand),
+    /// Report the versions of this tool and of the Materialize Cloud API.
+    Version,
+    /// Print the OpenAPI schema of the Materialize Cloud API.
+    Schema {
+        /// The format in which to fetch the schema.
+        #[clap(long, arg_enum, default_value = "json")]
+        format: SchemaFormat,
+
+        /// Save the schema to this file, rather than printing it.
+        #[clap(long)]
+        output_file: Option<PathBuf>,
+
+        /// Check that the schema is a well-formed OpenAPI 3 document, and
+        /// fail if it is not. Supported only for the JSON format.
+        #[clap(long)]
+        validate: bool,
+    },
+    /// Generate a completion script for a shell.
+    ///
+    /// The script is written to stdout. Redirect it into a file in your
+    /// shell's completions directory, e.g. for bash:
+    ///
+    ///     mzcloud completions bash > /etc/bash_completion.d/mzcloud
+    #[clap(setting = AppSettings::Hidden)]
+    Completions {
+        /// The shell for which to generate the script.
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
 }
 
 #[derive(Debug, clap::Parser)]
@@ -125,8 +557,10 @@ enum DeploymentsCommand {
     /// Create a new Materialize deployment.
     Create {
         /// Cloud provider:region pair in which to deploy Materialize. Example: `aws:us-east-1`
+        ///
+        /// Defaults to the global `--region`.
         #[structopt(long, parse(try_from_str = parse_cloud_region))]
-        cloud_provider_region: SupportedCloudRegionRequest,
+        cloud_provider_region: Option<SupportedCloudRegionRequest>,
 
         /// Name of the deployed materialized instance. Defaults to randomly assigned.
         #[clap(long)]
@@ -155,6 +589,15 @@ enum DeploymentsCommand {
         /// Enable Tailscale by setting the Tailscale Auth Key.
         #[clap(long)]
         tailscale_auth_key: Option<String>,
+
+        /// Wait until the deployment is ready before exiting.
+        #[clap(long)]
+        wait: bool,
+
+        /// The maximum number of seconds to wait for the deployment to become
+        /// ready, with `--wait`.
+        #[clap(long, default_value = "600", requires = "wait")]
+        timeout: u64,
     },
 
     /// Describe a Materialize deployment.
@@ -199,14 +642,44 @@ enum DeploymentsCommand {
         tailscale_auth_key: Option<String>,
     },
 
+    /// Change the size of a Materialize deployment, leaving its version and
+    /// other settings unchanged.
+    Scale {
+        /// ID of the deployment.
+        id: String,
+
+        /// New size of the deployment.
+        #[clap(parse(try_from_str = parse_size))]
+        size: DeploymentSizeEnum,
+    },
+
     /// Destroy a Materialize deployment.
+    ///
+    /// If stdout is a terminal, asks for the ID of the deployment to be typed
+    /// again to confirm.
     Destroy {
         /// ID of the deployment.
         id: String,
+
+        /// Destroy the deployment without asking for confirmation.
+        #[clap(short, long)]
+        yes: bool,
     },
 
     /// List existing Materialize deployments.
-    List,
+    List {
+        /// List at most this many deployments.
+        #[clap(long)]
+        limit: Option<usize>,
+
+        /// List only deployments with this status, in any case.
+        #[clap(long)]
+        status: Option<String>,
+
+        /// List only deployments of this size.
+        #[clap(long, parse(try_from_str = parse_size))]
+        size: Option<DeploymentSizeEnum>,
+    },
 
     /// Download the certificates bundle for a Materialize deployment.
     Certs {
@@ -215,6 +688,16 @@ enum DeploymentsCommand {
         /// Path to save the certs bundle to.
         #[clap(short, long, default_value = "mzcloud-certs.zip")]
         output_file: String,
+
+        /// Extract the certificates and keys from the bundle into this
+        /// directory, rather than saving the bundle itself.
+        #[clap(long)]
+        extract: Option<PathBuf>,
+
+        /// With `--extract`, extract into the directory even if it is not
+        /// empty, overwriting any files with the same names.
+        #[clap(long, requires = "extract")]
+        force: bool,
     },
 
     /// Download the logs from a Materialize deployment.
@@ -225,6 +708,14 @@ enum DeploymentsCommand {
         /// Get the logs for the previous execution, rather than the currently running one.
         #[clap(long)]
         previous: bool,
+
+        /// Keep printing new log lines as they are written, until interrupted.
+        #[clap(short, long, conflicts_with = "previous")]
+        follow: bool,
+
+        /// The number of seconds between fetches of the logs, with `--follow`.
+        #[clap(long, default_value = "2", requires = "follow")]
+        interval: u64,
     },
 
     /// Download the logs from a Materialize deployment.
@@ -237,12 +728,31 @@ enum DeploymentsCommand {
         previous: bool,
     },
 
+    /// Compare the configuration of two Materialize deployments.
+    Diff {
+        /// ID of the first deployment.
+        id_a: String,
+
+        /// ID of the second deployment.
+        id_b: String,
+    },
+
     /// Connect to a Materialize deployment using psql.
     /// Requires psql to be on your PATH.
     Psql {
         /// ID of the deployment.
         id: String,
     },
+
+    /// Print a psql command that connects to a Materialize deployment.
+    Connect {
+        /// ID of the deployment.
+        id: String,
+
+        /// Print only the hostname of the deployment.
+        #[clap(long)]
+        hostname_only: bool,
+    },
 }
 
 #[derive(Debug, clap::Parser)]
@@ -270,31 +780,897 @@ fn parse_cloud_region(s: &str) -> Result<SupportedCloudRegionRequest, String> {
     }
 }
 
+/// Parses a deployment size, either abbreviated (e.g. `XS`) or spelled out
+/// (e.g. `extra-small`), in any case.
 fn parse_size(s: &str) -> Result<DeploymentSizeEnum, String> {
-    match s {
-        "XS" => Ok(DeploymentSizeEnum::XS),
-        "S" => Ok(DeploymentSizeEnum::S),
-        "M" => Ok(DeploymentSizeEnum::M),
-        "L" => Ok(DeploymentSizeEnum::L),
-        "XL" => Ok(DeploymentSizeEnum::XL),
-        _ => Err("Invalid size.".to_owned()),
+    match s.to_uppercase().as_str() {
+        "XS" | "EXTRA-SMALL" => Ok(DeploymentSizeEnum::XS),
+        "S" | "SMALL" => Ok(DeploymentSizeEnum::S),
+        "M" | "MEDIUM" => Ok(DeploymentSizeEnum::M),
+        "L" | "LARGE" => Ok(DeploymentSizeEnum::L),
+        "XL" | "EXTRA-LARGE" => Ok(DeploymentSizeEnum::XL),
+        _ => Err(format!(
+            "Invalid size `{}`. Valid sizes are XS, S, M, L, and XL, or extra-small, small, medium, large, and extra-large.",
+            s
+        )),
+    }
+}
+
+/// Renders the `id` field of an object, or of each object in an array, one
+/// per line. Returns `None` if `value` is neither an object with an ID nor an
+/// array of them.
+fn render_ids(value: &Value) -> Option<String> {
+    let objects = match value {
+        Value::Array(elements) => elements.iter().collect(),
+        value => vec![value],
+    };
+    let mut out = String::new();
+    for object in objects {
+        out += object.get("id")?.as_str()?;
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// Renders a JSON value as a YAML document.
+fn render_yaml(value: &Value) -> String {
+    let mut out = String::new();
+    for line in yaml_lines(value) {
+        out += &line;
+        out.push('\n');
+    }
+    out
+}
+
+/// Renders a JSON value as the lines of a YAML node, without indentation.
+fn yaml_lines(value: &Value) -> Vec<String> {
+    // Nested nodes are indented beneath their parent.
+    fn nest(lines: Vec<String>, first_prefix: &str) -> impl Iterator<Item = String> + '_ {
+        lines.into_iter().enumerate().map(move |(i, line)| match i {
+            0 => format!("{}{}", first_prefix, line),
+            _ => format!("  {}", line),
+        })
+    }
+    match value {
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .flat_map(|item| nest(yaml_lines(item), "- ").collect::<Vec<_>>())
+            .collect(),
+        Value::Object(map) if !map.is_empty() => {
+            let mut lines = vec![];
+            for (key, value) in map {
+                let plain = key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                let key = match plain && !key.is_empty() {
+                    true => key.clone(),
+                    false => Value::String(key.clone()).to_string(),
+                };
+                match value {
+                    Value::Array(items) if !items.is_empty() => {
+                        lines.push(format!("{}:", key));
+                        lines.extend(nest(yaml_lines(value), "  "));
+                    }
+                    Value::Object(map) if !map.is_empty() => {
+                        lines.push(format!("{}:", key));
+                        lines.extend(nest(yaml_lines(value), "  "));
+                    }
+                    _ => lines.push(format!("{}: {}", key, yaml_scalar(value))),
+                }
+            }
+            lines
+        }
+        _ => vec![yaml_scalar(value)],
+    }
+}
+
+/// Renders a JSON scalar, or an empty array or object, as YAML.
+///
+/// JSON's literals and double-quoted strings are also valid YAML.
+fn yaml_scalar(value: &Value) -> String {
+    value.to_string()
+}
+
+/// Renders a JSON value as an aligned table.
+///
+/// A list of objects is rendered with a row per object and the given columns,
+/// or, if there are none, a column per field of any object. Other lists are
+/// rendered with a row per element. An object is rendered with a row per
+/// field. Nested fields are flattened as in `flatten_json`.
+fn render_table(value: Value, columns: &[Column]) -> String {
+    let (headers, rows): (Vec<String>, Vec<Vec<String>>) = match value {
+        Value::Array(items) if items.iter().all(Value::is_object) => {
+            let items: Vec<_> = items
+                .into_iter()
+                .map(|item| {
+                    let mut fields = BTreeMap::new();
+                    flatten_json("", item, &mut fields);
+                    fields
+                })
+                .collect();
+            let columns: Vec<(String, String)> = match columns {
+                [] => {
+                    let mut fields: Vec<_> = items.iter().flat_map(|item| item.keys()).collect();
+                    fields.sort();
+                    fields.dedup();
+                    fields
+                        .into_iter()
+                        .map(|field| (field.to_uppercase(), field.clone()))
+                        .collect()
+                }
+                columns => columns
+                    .iter()
+                    .map(|(header, field)| (header.to_string(), field.to_string()))
+                    .collect(),
+            };
+            let rows = items
+                .iter()
+                .map(|item| {
+                    columns
+                        .iter()
+                        .map(|(_, field)| item.get(field).map(table_cell).unwrap_or_default())
+                        .collect()
+                })
+                .collect();
+            (
+                columns.into_iter().map(|(header, _)| header).collect(),
+                rows,
+            )
+        }
+        Value::Array(items) => (
+            vec!["VALUE".into()],
+            items.iter().map(|item| vec![table_cell(item)]).collect(),
+        ),
+        Value::Object(_) => {
+            let mut fields = BTreeMap::new();
+            flatten_json("", value, &mut fields);
+            let rows = fields
+                .into_iter()
+                .map(|(field, value)| vec![field, table_cell(&value)])
+                .collect();
+            (vec!["FIELD".into(), "VALUE".into()], rows)
+        }
+        value => return format!("{}\n", table_cell(&value)),
+    };
+    let mut widths: Vec<_> = headers.iter().map(|header| header.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in iter::once(&headers).chain(&rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        out += cells.join("  ").trim_end();
+        out.push('\n');
+    }
+    out
+}
+
+/// Renders a JSON value as a table cell: strings without quotes, and nulls as
+/// empty cells.
+fn table_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// The fields whose values differ between two deployments.
+#[derive(Debug, Serialize)]
+struct DeploymentDiff {
+    id_a: String,
+    id_b: String,
+    fields: Vec<FieldDiff>,
+}
+
+/// A field whose value differs between two deployments.
+#[derive(Debug, Serialize)]
+struct FieldDiff {
+    /// The path to the field, with nested fields separated by dots.
+    field: String,
+    /// The value in the first deployment, if the field is present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<Value>,
+    /// The value in the second deployment, if the field is present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    b: Option<Value>,
+}
+
+/// Flattens a JSON value into a map from dotted field paths to leaf values.
+fn flatten_json(prefix: &str, value: Value, out: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map {
+                let path = match prefix {
+                    "" => k,
+                    _ => format!("{}.{}", prefix, k),
+                };
+                flatten_json(&path, v, out);
+            }
+        }
+        value => {
+            out.insert(prefix.to_owned(), value);
+        }
+    }
+}
+
+/// Computes the fields that differ between two serializable values.
+fn diff_fields<T: Serialize>(a: &T, b: &T) -> anyhow::Result<Vec<FieldDiff>> {
+    let mut a_fields = BTreeMap::new();
+    let mut b_fields = BTreeMap::new();
+    flatten_json("", serde_json::to_value(a)?, &mut a_fields);
+    flatten_json("", serde_json::to_value(b)?, &mut b_fields);
+    let mut fields: Vec<_> = a_fields.keys().chain(b_fields.keys()).cloned().collect();
+    fields.sort();
+    fields.dedup();
+    Ok(fields
+        .into_iter()
+        .filter_map(|field| {
+            let a = a_fields.remove(&field);
+            let b = b_fields.remove(&field);
+            (a != b).then(|| FieldDiff { field, a, b })
+        })
+        .collect())
+}
+
+/// Renders the differences between two deployments in the style of a unified
+/// diff.
+fn render_diff(diff: &DeploymentDiff) -> String {
+    let mut out = format!("--- {}\n+++ {}\n", diff.id_a, diff.id_b);
+    for FieldDiff { field, a, b } in &diff.fields {
+        out += &format!("@@ {} @@\n", field);
+        if let Some(a) = a {
+            out += &format!("-{}\n", a);
+        }
+        if let Some(b) = b {
+            out += &format!("+{}\n", b);
+        }
+    }
+    out
+}
+
+/// The backoff before the first retry of a failed API request.
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The maximum backoff between retries of a failed API request.
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Retries API requests that fail with transient errors, with exponential
+/// backoff and jitter.
+#[derive(Debug, Clone, Copy)]
+struct Retrier {
+    /// The maximum number of retries of each request.
+    max_retries: u32,
+}
+
+impl Retrier {
+    /// Issues the request made by `f`, retrying it if it is rate limited or
+    /// fails with a server or network error.
+    ///
+    /// `operation` names the request in log messages. A final error is
+    /// annotated with its [`FailureClass`], if any.
+    async fn idempotent<F, U, T, E>(&self, operation: &str, f: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> U,
+        U: Future<Output = Result<T, mzcloud::apis::Error<E>>>,
+        T: fmt::Debug,
+        E: fmt::Debug,
+        mzcloud::apis::Error<E>: std::error::Error + Send + Sync + 'static,
+    {
+        self.retry(operation, true, f)
+            .await
+            .map_err(classify_api_error)
+    }
+
+    /// Like [`Retrier::idempotent`], but for requests that must not take
+    /// effect twice. These are retried only after failures that guarantee
+    /// that the request did not take effect: rate limiting, and failures to
+    /// connect.
+    async fn non_idempotent<F, U, T, E>(&self, operation: &str, f: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> U,
+        U: Future<Output = Result<T, mzcloud::apis::Error<E>>>,
+        T: fmt::Debug,
+        E: fmt::Debug,
+        mzcloud::apis::Error<E>: std::error::Error + Send + Sync + 'static,
+    {
+        self.retry(operation, false, f)
+            .await
+            .map_err(classify_api_error)
     }
+
+    async fn retry<F, U, T, E>(
+        &self,
+        operation: &str,
+        idempotent: bool,
+        mut f: F,
+    ) -> Result<T, mzcloud::apis::Error<E>>
+    where
+        F: FnMut() -> U,
+        U: Future<Output = Result<T, mzcloud::apis::Error<E>>>,
+        T: fmt::Debug,
+        E: fmt::Debug,
+    {
+        let mut backoff = RETRY_INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let start = Instant::now();
+            let result = f().await;
+            log_api_result(operation, start.elapsed(), &result);
+            let error = match result {
+                Ok(t) => return Ok(t),
+                Err(error) => error,
+            };
+            let reason = match retry_reason(&error, idempotent) {
+                Some(reason) if retries < self.max_retries => reason,
+                _ => return Err(error),
+            };
+            retries += 1;
+            // Sleep for between half and all of the backoff, so that clients
+            // that failed at the same time do not all retry at the same time.
+            let delay = backoff / 2 + rand::thread_rng().gen_range(Duration::ZERO..=backoff / 2);
+            eprintln!(
+                "Request failed ({}); retrying in {:.1?} (retry {} of {})...",
+                reason, delay, retries, self.max_retries
+            );
+            time::sleep(delay).await;
+            backoff = cmp::min(backoff * 2, RETRY_MAX_BACKOFF);
+        }
+    }
+}
+
+/// Logs the outcome of an API request named `operation` that took `elapsed`.
+///
+/// The outcome is logged at debug level, and the response body at trace
+/// level. Neither includes the request headers, which carry the access token.
+fn log_api_result<T, E>(
+    operation: &str,
+    elapsed: Duration,
+    result: &Result<T, mzcloud::apis::Error<E>>,
+) where
+    T: fmt::Debug,
+    E: fmt::Debug,
+{
+    match result {
+        Ok(value) => {
+            debug!("{}: succeeded in {:?}", operation, elapsed);
+            trace!("{}: response: {:?}", operation, value);
+        }
+        Err(mzcloud::apis::Error::ResponseError(response)) => {
+            debug!(
+                "{}: failed with HTTP {} in {:?}",
+                operation, response.status, elapsed
+            );
+            trace!("{}: response: {}", operation, response.content);
+        }
+        Err(mzcloud::apis::Error::Reqwest(e)) => {
+            debug!("{}: failed in {:?}: {}", operation, elapsed, e);
+        }
+        Err(e) => debug!("{}: failed in {:?}: {:?}", operation, elapsed, e),
+    }
+}
+
+/// Describes `error`, if a request that failed with it may be retried.
+///
+/// Requests that are not `idempotent` may only be retried if the error
+/// guarantees that the server did not act on them.
+fn retry_reason<E>(error: &mzcloud::apis::Error<E>, idempotent: bool) -> Option<String> {
+    match error {
+        mzcloud::apis::Error::ResponseError(response) => {
+            let status = response.status;
+            let retryable =
+                status == StatusCode::TOO_MANY_REQUESTS || (idempotent && status.is_server_error());
+            retryable.then(|| format!("HTTP {}", status))
+        }
+        mzcloud::apis::Error::Reqwest(e) => {
+            // A request that failed to connect was never sent, but one that
+            // failed later may have been received by the server.
+            let retryable = e.is_connect() || (idempotent && (e.is_timeout() || e.is_request()));
+            retryable.then(|| e.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// A class of failure that scripts may want to handle specially, and which
+/// therefore has its own exit code.
+///
+/// Errors are annotated with their class as [`anyhow::Context`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureClass {
+    /// The credentials were missing or rejected.
+    Auth,
+    /// The requested resource does not exist.
+    NotFound,
+    /// The API could not be reached.
+    Network,
+    /// A request to the API timed out.
+    Timeout,
+    /// The API rejected the request as invalid.
+    Validation,
+}
+
+impl FailureClass {
+    /// Returns the exit code of the process when it fails with this class of
+    /// failure.
+    ///
+    /// Exit code 1 is used for all other failures, and exit code 2 for
+    /// invalid command-line arguments. Keep [`EXIT_CODES`] in sync.
+    fn exit_code(self) -> i32 {
+        match self {
+            FailureClass::Auth => 3,
+            FailureClass::NotFound => 4,
+            FailureClass::Network | FailureClass::Timeout => 5,
+            FailureClass::Validation => 6,
+        }
+    }
+
+    /// Returns the class of a failed HTTP request, if any.
+    fn of_reqwest_error(error: &reqwest::Error) -> Option<FailureClass> {
+        match error.status() {
+            Some(status) => FailureClass::of_status(status),
+            None if error.is_timeout() => Some(FailureClass::Timeout),
+            None if error.is_connect() || error.is_request() => Some(FailureClass::Network),
+            None => None,
+        }
+    }
+
+    /// Returns the class of a request that failed with HTTP status `status`,
+    /// if any.
+    fn of_status(status: StatusCode) -> Option<FailureClass> {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(FailureClass::Auth),
+            StatusCode::NOT_FOUND => Some(FailureClass::NotFound),
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+                Some(FailureClass::Validation)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureClass::Auth => f.write_str("authentication failed"),
+            FailureClass::NotFound => f.write_str("not found"),
+            FailureClass::Network => f.write_str("unable to reach the API"),
+            FailureClass::Timeout => f.write_str("request timed out"),
+            FailureClass::Validation => f.write_str("invalid request"),
+        }
+    }
+}
+
+/// Converts an error returned by the API client into an [`anyhow::Error`]
+/// annotated with its [`FailureClass`], if any.
+fn classify_api_error<E>(error: mzcloud::apis::Error<E>) -> anyhow::Error
+where
+    mzcloud::apis::Error<E>: std::error::Error + Send + Sync + 'static,
+{
+    let class = match &error {
+        mzcloud::apis::Error::ResponseError(response) => FailureClass::of_status(response.status),
+        mzcloud::apis::Error::Reqwest(e) => FailureClass::of_reqwest_error(e),
+        _ => None,
+    };
+    with_failure_class(error.into(), class)
+}
+
+/// Annotates `error` with `class`, if any.
+fn with_failure_class(error: anyhow::Error, class: Option<FailureClass>) -> anyhow::Error {
+    match class {
+        Some(class) => error.context(class),
+        None => error,
+    }
+}
+
+/// Returns the exit code of the process when it fails with `error`.
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<FailureClass>() {
+        Some(class) => class.exit_code(),
+        None => 1,
+    }
+}
+
+/// The status of a deployment that is ready for use.
+const READY_STATUS: &str = "OK";
+
+/// The interval at which to poll a deployment that is not yet ready.
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Polls a newly created deployment until its status is ready or `timeout`
+/// elapses, reporting its status to stderr as it changes.
+///
+/// Returns the last known state of the deployment, and whether it is ready.
+async fn wait_until_ready(
+    config: &Configuration,
+    retrier: &Retrier,
+    mut deployment: Deployment,
+    timeout: Duration,
+) -> anyhow::Result<(Deployment, bool)> {
+    let deadline = Instant::now() + timeout;
+    let mut last_status = None;
+    loop {
+        let status = serde_json::to_value(&deployment)?["status"]
+            .as_str()
+            .map(str::to_owned);
+        if status.as_deref() == Some(READY_STATUS) {
+            eprintln!("Deployment {} is ready.", deployment.id);
+            return Ok((deployment, true));
+        }
+        if status != last_status {
+            eprintln!(
+                "Waiting for deployment {} to become ready (status: {})...",
+                deployment.id,
+                status.as_deref().unwrap_or("unknown")
+            );
+            last_status = status;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok((deployment, false));
+        }
+        time::sleep(READY_POLL_INTERVAL.min(deadline - now)).await;
+        let id = deployment.id;
+        deployment = retrier
+            .idempotent("deployments_retrieve", || deployments_retrieve(config, &id))
+            .await?;
+    }
+}
+
+/// Prints the logs of a deployment, then fetches them every `interval` and
+/// prints whatever has been appended since, like `tail -f`. Never returns
+/// unless fetching the logs fails.
+async fn follow_logs(
+    config: &Configuration,
+    retrier: &Retrier,
+    id: &str,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut seen = 0;
+    loop {
+        let logs = retrier
+            .idempotent("deployments_logs_retrieve", || {
+                deployments_logs_retrieve(config, id, Some(false))
+            })
+            .await?;
+        let new = match new_log_output(&logs, seen) {
+            Some(new) => new,
+            None => {
+                eprintln!("Logs were truncated; printing them from the beginning.");
+                &logs
+            }
+        };
+        let mut stdout = io::stdout();
+        stdout.write_all(new.as_bytes())?;
+        stdout.flush()?;
+        seen = logs.len();
+        time::sleep(interval).await;
+    }
+}
+
+/// Returns the output appended to `logs` after the first `seen` bytes, or
+/// `None` if the logs are shorter than that, as happens when they are rotated.
+///
+/// Logs that were rotated and have since grown past `seen` are not detected,
+/// unless `seen` falls inside a character, in which case `None` is returned
+/// as well.
+fn new_log_output(logs: &str, seen: usize) -> Option<&str> {
+    logs.get(seen..)
+}
+
+/// The fields of request bodies that hold secrets, as serialized by the SDK,
+/// which are redacted from the output of `--dry-run`.
+const SECRET_FIELDS: &[&str] = &["tailscaleAuthKey"];
+
+/// Prints the request that a command would make, for `--dry-run`.
+///
+/// The values of any [`SECRET_FIELDS`] in `body` are redacted.
+fn print_dry_run<T: Serialize>(
+    printer: &Printer,
+    method: &str,
+    url: &str,
+    body: Option<&T>,
+) -> anyhow::Result<()> {
+    #[derive(Serialize)]
+    struct DryRun<'a> {
+        method: &'a str,
+        url: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        body: Option<Value>,
+    }
+    let body = body.map(redact_secrets).transpose()?;
+    printer.print_value(&DryRun { method, url, body })
+}
+
+/// Serializes `body`, replacing the value of each of the [`SECRET_FIELDS`]
+/// that is set with a placeholder.
+fn redact_secrets<T: Serialize>(body: &T) -> anyhow::Result<Value> {
+    let mut body = serde_json::to_value(body)?;
+    if let Value::Object(fields) = &mut body {
+        for field in SECRET_FIELDS {
+            if let Some(value) = fields.get_mut(*field) {
+                if !value.is_null() {
+                    *value = Value::from("<redacted>");
+                }
+            }
+        }
+    }
+    Ok(body)
+}
+
+/// Reports whether `deployment` has the given status, compared
+/// case-insensitively, and the given size, as serialized, if any.
+fn deployment_matches(
+    deployment: &Deployment,
+    status: Option<&str>,
+    size: Option<&Value>,
+) -> anyhow::Result<bool> {
+    let value = serde_json::to_value(deployment)?;
+    if let Some(status) = status {
+        match value["status"].as_str() {
+            Some(actual) if actual.eq_ignore_ascii_case(status) => (),
+            _ => return Ok(false),
+        }
+    }
+    Ok(size.map_or(true, |size| value["size"] == *size))
+}
+
+/// Extracts the files in the certificates bundle `bytes` into `dir`, creating
+/// it if necessary, and returns their paths.
+///
+/// Fails if `dir` is not empty, unless `force` is set.
+fn extract_certs(bytes: Vec<u8>, dir: &Path, force: bool) -> anyhow::Result<Vec<PathBuf>> {
+    if !force && dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        bail!(
+            "Directory {} is not empty. Pass --force to extract the certificates into it anyway.",
+            dir.display()
+        );
+    }
+    fs::create_dir_all(dir)?;
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    archive.extract(dir)?;
+    let mut paths = vec![];
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        // `extract` has already rejected files with unsafe names.
+        if let Some(name) = file.enclosed_name() {
+            if !file.is_dir() {
+                paths.push(dir.join(name));
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Describes `deployment` on stderr and asks for its ID to be typed to
+/// confirm its destruction.
+///
+/// Returns whether the ID was typed correctly.
+fn confirm_destroy(deployment: &Deployment) -> anyhow::Result<bool> {
+    let value = serde_json::to_value(deployment)?;
+    eprintln!("About to destroy deployment {}:", deployment.id);
+    for (label, field) in [("Name", "name"), ("Size", "size"), ("Version", "mzVersion")] {
+        eprintln!("  {}: {}", label, table_cell(&value[field]));
+    }
+    eprint!("Type the deployment ID to confirm: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim() == deployment.id)
+}
+
+/// A format in which the API serves its OpenAPI schema.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum SchemaFormat {
+    Json,
+    Yaml,
+}
+
+impl SchemaFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            SchemaFormat::Json => "json",
+            SchemaFormat::Yaml => "yaml",
+        }
+    }
+}
+
+/// Fetches the version of the Materialize Cloud API.
+///
+/// The API does not have a dedicated version endpoint, so this reports the
+/// version advertised in its OpenAPI schema.
+async fn server_version(config: &Configuration) -> anyhow::Result<String> {
+    let schema: Value = serde_json::from_str(&fetch_schema(config, SchemaFormat::Json).await?)?;
+    schema["info"]["version"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("API schema does not specify a version."))
+}
+
+/// Fetches the OpenAPI schema of the Materialize Cloud API in `format`.
+async fn fetch_schema(config: &Configuration, format: SchemaFormat) -> anyhow::Result<String> {
+    let mut req = config
+        .client
+        .get(format!("{}/api/schema/", config.base_path))
+        .query(&[("format", format.as_str())]);
+    if let Some(user_agent) = &config.user_agent {
+        req = req.header(reqwest::header::USER_AGENT, user_agent);
+    }
+    if let Some(token) = &config.bearer_access_token {
+        req = req.bearer_auth(token);
+    }
+    let start = Instant::now();
+    let response = req.send().await?;
+    debug!(
+        "GET {}: HTTP {} in {:?}",
+        response.url(),
+        response.status(),
+        start.elapsed()
+    );
+    Ok(response.error_for_status()?.text().await?)
+}
+
+/// The HTTP methods that may have operations in an OpenAPI path item.
+const OPENAPI_METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// Checks that `schema` has the structure of an OpenAPI 3 document: an
+/// `openapi` version of 3.x, an `info` object with a title and version, and
+/// a `paths` object whose keys are paths and whose operations have
+/// responses.
+///
+/// Returns a description of each problem found, if any.
+fn validate_openapi(schema: &Value) -> Result<(), Vec<String>> {
+    let mut problems = vec![];
+    match schema["openapi"].as_str() {
+        Some(version) if version.starts_with("3.") => (),
+        Some(version) => problems.push(format!("unsupported OpenAPI version {}", version)),
+        None => problems.push("missing `openapi` version".into()),
+    }
+    for field in ["title", "version"] {
+        if !schema["info"][field].is_string() {
+            problems.push(format!("missing `info.{}`", field));
+        }
+    }
+    match schema["paths"].as_object() {
+        Some(paths) => {
+            for (path, item) in paths {
+                if !path.starts_with('/') {
+                    problems.push(format!("path `{}` does not start with `/`", path));
+                }
+                for method in OPENAPI_METHODS {
+                    let operation = &item[*method];
+                    if !operation.is_null() && !operation["responses"].is_object() {
+                        problems.push(format!(
+                            "operation `{} {}` has no responses",
+                            method.to_uppercase(),
+                            path
+                        ));
+                    }
+                }
+            }
+        }
+        None => problems.push("missing `paths` object".into()),
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+async fn handle_schema(
+    config: &Configuration,
+    printer: &Printer,
+    format: SchemaFormat,
+    output_file: Option<PathBuf>,
+    validate: bool,
+) -> anyhow::Result<()> {
+    if validate && format != SchemaFormat::Json {
+        bail!("--validate is supported only with --format json.");
+    }
+    let schema = fetch_schema(config, format).await?;
+    if validate {
+        let value = serde_json::from_str(&schema).context("parsing schema")?;
+        if let Err(problems) = validate_openapi(&value) {
+            bail!("Invalid OpenAPI schema:\n  {}", problems.join("\n  "));
+        }
+    }
+    match output_file {
+        Some(path) => {
+            fs::write(&path, &schema)?;
+            println!("Schema saved to {}", path.display());
+        }
+        None => printer.print(&schema)?,
+    }
+    Ok(())
+}
+
+/// Reports whether a client and server version are compatible.
+///
+/// Versions are compatible if they agree on the major version, or, for
+/// pre-1.0 versions, on the major and minor version. Returns `None` if either
+/// version is not of the form `MAJOR.MINOR[.PATCH]`.
+fn versions_compatible(client: &str, server: &str) -> Option<bool> {
+    fn major_minor(version: &str) -> Option<(u64, u64)> {
+        let mut parts = version.trim_start_matches('v').split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((major, minor))
+    }
+    let client = major_minor(client)?;
+    let server = major_minor(server)?;
+    Some(match client.0 {
+        0 => client == server,
+        _ => client.0 == server.0,
+    })
+}
+
+/// The versions reported by `mzcloud version`.
+#[derive(Debug, Serialize)]
+struct VersionReport {
+    client: &'static str,
+    /// The version of the API, if it could be fetched.
+    server: Option<String>,
+    /// Whether the client is compatible with the API, if known.
+    compatible: Option<bool>,
+}
+
+async fn handle_version(config: &Configuration, printer: &Printer) -> anyhow::Result<()> {
+    let server = server_version(config).await;
+    let report = VersionReport {
+        client: VERSION,
+        server: server.as_ref().ok().cloned(),
+        compatible: server
+            .as_ref()
+            .ok()
+            .and_then(|server| versions_compatible(VERSION, server)),
+    };
+    let mut out = format!("Client version: {}\n", report.client);
+    match &report.server {
+        Some(server) => {
+            out += &format!("Server version: {}\n", server);
+            let compatible = match report.compatible {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "unknown",
+            };
+            out += &format!("Compatible: {}\n", compatible);
+        }
+        None => out += "Server version: unknown\n",
+    }
+    printer.print_text_or_value(&out, &report)?;
+    server.map(|_| ())
 }
 
 async fn handle_mz_version_operations(
     config: &Configuration,
+    printer: &Printer,
+    retrier: &Retrier,
     operation: MzVersionsCommand,
 ) -> anyhow::Result<()> {
     Ok(match operation {
         MzVersionsCommand::List => {
-            let versions = mz_versions_list(&config).await?;
-            println!("{}", serde_json::to_string_pretty(&versions)?);
+            let versions = retrier
+                .idempotent("mz_versions_list", || mz_versions_list(config))
+                .await?;
+            printer.print_value(&versions)?;
         }
     })
 }
 
 async fn handle_deployment_operations(
     config: &Configuration,
+    printer: &Printer,
+    retrier: &Retrier,
+    dry_run: bool,
     operation: DeploymentsCommand,
 ) -> anyhow::Result<()> {
     Ok(match operation {
@@ -307,27 +1683,53 @@ async fn handle_deployment_operations(
             materialized_extra_args,
             mz_version,
             tailscale_auth_key,
+            wait,
+            timeout,
         } => {
-            let deployment = deployments_create(
-                &config,
-                DeploymentRequest {
-                    cloud_provider_region: Box::new(cloud_provider_region),
-                    name,
-                    size: size.map(Box::new),
-                    storage_mb,
-                    disable_user_indexes,
-                    materialized_extra_args,
-                    mz_version,
-                    enable_tailscale: Some(tailscale_auth_key.is_some()),
-                    tailscale_auth_key,
-                },
-            )
-            .await?;
-            println!("{}", serde_json::to_string_pretty(&deployment)?);
+            let cloud_provider_region = cloud_provider_region.ok_or_else(|| {
+                anyhow!("No region specified. Pass --cloud-provider-region or --region, or set MZCLOUD_REGION.")
+            })?;
+            let request = DeploymentRequest {
+                cloud_provider_region: Box::new(cloud_provider_region),
+                name,
+                size: size.map(Box::new),
+                storage_mb,
+                disable_user_indexes,
+                materialized_extra_args,
+                mz_version,
+                enable_tailscale: Some(tailscale_auth_key.is_some()),
+                tailscale_auth_key,
+            };
+            if dry_run {
+                let url = format!("{}/api/deployments/", config.base_path);
+                return print_dry_run(printer, "POST", &url, Some(&request));
+            }
+            let deployment = retrier
+                .non_idempotent("deployments_create", || {
+                    deployments_create(config, request.clone())
+                })
+                .await?;
+            if wait {
+                let timeout = Duration::from_secs(timeout);
+                let (deployment, ready) =
+                    wait_until_ready(config, retrier, deployment, timeout).await?;
+                printer.print_value(&deployment)?;
+                if !ready {
+                    bail!(
+                        "Timed out after {}s waiting for deployment {} to become ready.",
+                        timeout.as_secs(),
+                        deployment.id
+                    );
+                }
+            } else {
+                printer.print_value(&deployment)?;
+            }
         }
         DeploymentsCommand::Get { id } => {
-            let deployment = deployments_retrieve(&config, &id).await?;
-            println!("{}", serde_json::to_string_pretty(&deployment)?);
+            let deployment = retrier
+                .idempotent("deployments_retrieve", || deployments_retrieve(config, &id))
+                .await?;
+            printer.print_value(&deployment)?;
         }
         DeploymentsCommand::Update {
             id,
@@ -344,50 +1746,168 @@ async fn handle_deployment_operations(
                 (false, None) => None,
                 (false, Some(_)) => Some(true),
             };
-            let deployment = deployments_partial_update(
-                &config,
-                &id,
-                Some(PatchedDeploymentUpdateRequest {
-                    name,
-                    size: size.map(Box::new),
-                    storage_mb: None,
-                    disable_user_indexes,
-                    materialized_extra_args,
-                    mz_version,
-                    enable_tailscale,
-                    tailscale_auth_key,
-                }),
-            )
-            .await?;
-            println!("{}", serde_json::to_string_pretty(&deployment)?);
+            let request = PatchedDeploymentUpdateRequest {
+                name,
+                size: size.map(Box::new),
+                storage_mb: None,
+                disable_user_indexes,
+                materialized_extra_args,
+                mz_version,
+                enable_tailscale,
+                tailscale_auth_key,
+            };
+            if dry_run {
+                let url = format!("{}/api/deployments/{}/", config.base_path, id);
+                return print_dry_run(printer, "PATCH", &url, Some(&request));
+            }
+            let deployment = retrier
+                .idempotent("deployments_partial_update", || {
+                    deployments_partial_update(config, &id, Some(request.clone()))
+                })
+                .await?;
+            printer.print_value(&deployment)?;
         }
-        DeploymentsCommand::Destroy { id } => {
-            deployments_destroy(&config, &id).await?;
+        DeploymentsCommand::Scale { id, size } => {
+            // Fields left unset in a partial update keep their current values.
+            let request = PatchedDeploymentUpdateRequest {
+                name: None,
+                size: Some(Box::new(size)),
+                storage_mb: None,
+                disable_user_indexes: None,
+                materialized_extra_args: None,
+                mz_version: None,
+                enable_tailscale: None,
+                tailscale_auth_key: None,
+            };
+            if dry_run {
+                let url = format!("{}/api/deployments/{}/", config.base_path, id);
+                return print_dry_run(printer, "PATCH", &url, Some(&request));
+            }
+            let deployment = retrier
+                .idempotent("deployments_partial_update", || {
+                    deployments_partial_update(config, &id, Some(request.clone()))
+                })
+                .await?;
+            printer.print_value(&deployment)?;
         }
-        DeploymentsCommand::List => {
-            let deployments = deployments_list(&config).await?;
-            println!("{}", serde_json::to_string_pretty(&deployments)?);
+        DeploymentsCommand::Destroy { id, yes } => {
+            if dry_run {
+                let url = format!("{}/api/deployments/{}/", config.base_path, id);
+                return print_dry_run::<()>(printer, "DELETE", &url, None);
+            }
+            if !yes && atty::is(atty::Stream::Stdout) {
+                let deployment = retrier
+                    .idempotent("deployments_retrieve", || deployments_retrieve(config, &id))
+                    .await?;
+                if !confirm_destroy(&deployment)? {
+                    bail!("Deployment ID not confirmed; not destroying {}.", id);
+                }
+            }
+            retrier
+                .idempotent("deployments_destroy", || deployments_destroy(config, &id))
+                .await?;
         }
-        DeploymentsCommand::Certs { id, output_file } => {
-            let bytes = deployments_certs_retrieve(&config, &id).await?;
-            fs::write(&output_file, &bytes)?;
-            println!("Certificate bundle saved to {}", &output_file);
+        DeploymentsCommand::List {
+            limit,
+            status,
+            size,
+        } => {
+            // The API returns every deployment at once, rather than in pages.
+            let deployments = retrier
+                .idempotent("deployments_list", || deployments_list(config))
+                .await?;
+            let size = size.map(serde_json::to_value).transpose()?;
+            let mut matches = vec![];
+            for deployment in deployments {
+                if deployment_matches(&deployment, status.as_deref(), size.as_ref())? {
+                    matches.push(deployment);
+                }
+            }
+            let mut deployments = matches;
+            if let Some(limit) = limit {
+                deployments.truncate(limit);
+            }
+            printer.print_list(&deployments, DEPLOYMENT_COLUMNS)?;
         }
-        DeploymentsCommand::Logs { id, previous } => {
-            let logs = deployments_logs_retrieve(&config, &id, Some(previous)).await?;
-            print!("{}", logs);
+        DeploymentsCommand::Certs {
+            id,
+            output_file,
+            extract,
+            force,
+        } => {
+            let bytes = retrier
+                .idempotent("deployments_certs_retrieve", || {
+                    deployments_certs_retrieve(config, &id)
+                })
+                .await?;
+            match extract {
+                Some(dir) => {
+                    for path in extract_certs(bytes, &dir, force)? {
+                        println!("Saved {}", path.display());
+                    }
+                }
+                None => {
+                    fs::write(&output_file, &bytes)?;
+                    println!("Certificate bundle saved to {}", &output_file);
+                }
+            }
+        }
+        DeploymentsCommand::Logs {
+            id,
+            previous,
+            follow,
+            interval,
+        } => {
+            if follow {
+                follow_logs(config, retrier, &id, Duration::from_secs(interval)).await?;
+            } else {
+                let logs = retrier
+                    .idempotent("deployments_logs_retrieve", || {
+                        deployments_logs_retrieve(config, &id, Some(previous))
+                    })
+                    .await?;
+                printer.print(&logs)?;
+            }
         }
         DeploymentsCommand::TailscaleLogs { id, previous } => {
-            let logs = deployments_tailscale_logs_retrieve(&config, &id, Some(previous)).await?;
-            print!("{}", logs);
+            let logs = retrier
+                .idempotent("deployments_tailscale_logs_retrieve", || {
+                    deployments_tailscale_logs_retrieve(config, &id, Some(previous))
+                })
+                .await?;
+            printer.print(&logs)?;
+        }
+        DeploymentsCommand::Diff { id_a, id_b } => {
+            let deployment_a = retrier
+                .idempotent("deployments_retrieve", || {
+                    deployments_retrieve(config, &id_a)
+                })
+                .await?;
+            let deployment_b = retrier
+                .idempotent("deployments_retrieve", || {
+                    deployments_retrieve(config, &id_b)
+                })
+                .await?;
+            let diff = DeploymentDiff {
+                fields: diff_fields(&deployment_a, &deployment_b)?,
+                id_a,
+                id_b,
+            };
+            printer.print_text_or_value(&render_diff(&diff), &diff)?;
         }
         DeploymentsCommand::Psql { id } => {
-            let bytes = deployments_certs_retrieve(&config, &id).await?;
+            let bytes = retrier
+                .idempotent("deployments_certs_retrieve", || {
+                    deployments_certs_retrieve(config, &id)
+                })
+                .await?;
             let dir = tempfile::tempdir()?;
             let c = Cursor::new(bytes);
             let mut archive = ZipArchive::new(c)?;
             archive.extract(&dir)?;
-            let deployment = deployments_retrieve(&config, &id).await?;
+            let deployment = retrier
+                .idempotent("deployments_retrieve", || deployments_retrieve(config, &id))
+                .await?;
             let hostname = deployment
                 .hostname
                 .ok_or_else(|| anyhow!("Deployment does not have a hostname."))?;
@@ -395,12 +1915,31 @@ async fn handle_deployment_operations(
                 .path()
                 .to_str()
                 .ok_or_else(|| anyhow!("Unable to format postgresql connection string. Temp dir contains non-unicode characters."))?;
-            let postgres_url = format!("postgresql://materialize@{hostname}:6875/materialize?sslmode=require&sslcert={dir}/materialize.crt&sslkey={dir}/materialize.key&sslrootcert={dir}/ca.crt", hostname=hostname, dir=dir_str);
+            let postgres_url = format!("postgresql://materialize@{hostname}:{port}/materialize?sslmode=require&sslcert={dir}/materialize.crt&sslkey={dir}/materialize.key&sslrootcert={dir}/ca.crt", hostname=hostname, port=SQL_PORT, dir=dir_str);
             process::Command::new("psql")
                 .arg(postgres_url)
                 .spawn()?
                 .wait()?;
         }
+        DeploymentsCommand::Connect { id, hostname_only } => {
+            let deployment = retrier
+                .idempotent("deployments_retrieve", || deployments_retrieve(config, &id))
+                .await?;
+            let hostname = deployment
+                .hostname
+                .ok_or_else(|| anyhow!("Deployment does not have a hostname."))?;
+            if hostname_only {
+                printer.print(&format!("{}\n", hostname))?;
+            } else {
+                let postgres_url = format!("postgresql://materialize@{hostname}:{port}/materialize?sslmode=require&sslcert=materialize.crt&sslkey=materialize.key&sslrootcert=ca.crt", hostname=hostname, port=SQL_PORT);
+                printer.print(&format!("psql \"{}\"\n", postgres_url))?;
+                eprintln!(
+                    "The certificates are in the bundle downloaded by `mzcloud deployments certs {}`. \
+                     Run psql from the directory into which the bundle is extracted.",
+                    id
+                );
+            }
+        }
     })
 }
 
@@ -410,15 +1949,39 @@ struct OauthResponse {
     access_token: String,
 }
 
-async fn get_oauth_token(args: &Args) -> Result<String, reqwest::Error> {
-    Ok(reqwest::Client::new()
+async fn get_oauth_token(args: &Args, client: &reqwest::Client) -> anyhow::Result<String> {
+    if args.oauth.client_id.is_none() || args.oauth.secret.is_none() {
+        let error = anyhow!("An OAuth client ID and secret key are required. Pass --client-id and --secret or --secret-file, or set MZCLOUD_CLIENT_ID and MZCLOUD_SECRET_KEY or MZCLOUD_SECRET_FILE.");
+        return Err(error.context(FailureClass::Auth));
+    }
+    request_oauth_token(args, client).await.map_err(|error| {
+        let class = FailureClass::of_reqwest_error(&error);
+        with_failure_class(error.into(), class)
+    })
+}
+
+async fn request_oauth_token(
+    args: &Args,
+    client: &reqwest::Client,
+) -> Result<String, reqwest::Error> {
+    let start = Instant::now();
+    let response = client
         .post(format!(
             "{}/identity/resources/auth/v1/api-token",
             args.admin_url()
         ))
         .json(&args.oauth)
         .send()
-        .await?
+        .await?;
+    // Neither the request nor the response body is logged, as they contain
+    // the credentials and the access token.
+    debug!(
+        "POST {}: HTTP {} in {:?}",
+        response.url(),
+        response.status(),
+        start.elapsed()
+    );
+    Ok(response
         .error_for_status()?
         .json::<OauthResponse>()
         .await?
@@ -426,22 +1989,73 @@ async fn get_oauth_token(args: &Args) -> Result<String, reqwest::Error> {
 }
 
 async fn run() -> anyhow::Result<()> {
-    let args = ore::cli::parse_args();
+    let mut args: Args = ore::cli::parse_args();
+    let max_level = match args.verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    // Only this tool's own events are logged, as those of the HTTP client
+    // libraries may include the access token.
+    tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::TRACE)
+        .with_writer(io::stderr)
+        .finish()
+        .with(Targets::new().with_target(module_path!(), max_level))
+        .init();
+
+    if let Category::Completions { shell } = args.category {
+        clap_complete::generate(shell, &mut Args::into_app(), "mzcloud", &mut io::stdout());
+        return Ok(());
+    }
 
-    let access_token = get_oauth_token(&args).await?;
+    args.oauth.read_secret_file()?;
+    let config_file = ConfigFile::load(args.config.as_deref())?;
+    args.apply_config_file(config_file)?;
+
+    let client = args.http_client()?;
+    let access_token = if args.is_dry_run() {
+        None
+    } else {
+        Some(get_oauth_token(&args, &client).await?)
+    };
     let config = Configuration {
+        client,
         base_path: args.url(),
         user_agent: Some(format!("mzcloud-cli/{}/rust", VERSION)),
         // Yes, this came from OAuth, but Frontegg wants it as a bearer token.
-        bearer_access_token: Some(access_token),
+        bearer_access_token: access_token,
         ..Default::default()
     };
+    debug!("using the API at {}", config.base_path);
+    let printer = args.printer();
+    let retrier = args.retrier();
+
+    if let Category::Deployments(DeploymentsCommand::Create {
+        cloud_provider_region,
+        ..
+    }) = &mut args.category
+    {
+        if cloud_provider_region.is_none() {
+            *cloud_provider_region = args.region.take();
+        }
+    }
 
     Ok(match args.category {
         Category::Deployments(operation) => {
-            handle_deployment_operations(&config, operation).await?
+            handle_deployment_operations(&config, &printer, &retrier, args.dry_run, operation)
+                .await?
         }
-        Category::MzVersions(operation) => handle_mz_version_operations(&config, operation).await?,
+        Category::MzVersions(operation) => {
+            handle_mz_version_operations(&config, &printer, &retrier, operation).await?
+        }
+        Category::Version => handle_version(&config, &printer).await?,
+        Category::Schema {
+            format,
+            output_file,
+            validate,
+        } => handle_schema(&config, &printer, format, output_file, validate).await?,
+        Category::Completions { .. } => unreachable!("handled above"),
     })
 }
 
@@ -449,6 +2063,176 @@ async fn run() -> anyhow::Result<()> {
 async fn main() {
     if let Err(e) = run().await {
         eprintln!("error: {:#?}", e);
-        process::exit(1);
+        process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use mzcloud::models::deployment_request::DeploymentRequest;
+    use mzcloud::models::deployment_size_enum::DeploymentSizeEnum;
+    use serde_json::json;
+
+    use clap::IntoApp;
+
+    use super::{
+        diff_fields, flatten_json, parse_cloud_region, parse_size, redact_secrets,
+        validate_openapi, versions_compatible, Args, FailureClass, EXIT_CODES,
+    };
+
+    #[test]
+    fn test_exit_codes_in_help() {
+        let mut help = vec![];
+        Args::into_app().write_long_help(&mut help).unwrap();
+        let help = String::from_utf8(help).unwrap();
+        assert!(help.trim_end().ends_with(EXIT_CODES), "{}", help);
+        for class in [
+            FailureClass::Auth,
+            FailureClass::NotFound,
+            FailureClass::Network,
+            FailureClass::Timeout,
+            FailureClass::Validation,
+        ] {
+            let line = format!("\n    {}    ", class.exit_code());
+            assert!(EXIT_CODES.contains(&line), "{:?}", class);
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        for (input, size) in [
+            ("XS", DeploymentSizeEnum::XS),
+            ("xs", DeploymentSizeEnum::XS),
+            ("Extra-Small", DeploymentSizeEnum::XS),
+            ("s", DeploymentSizeEnum::S),
+            ("small", DeploymentSizeEnum::S),
+            ("M", DeploymentSizeEnum::M),
+            ("MEDIUM", DeploymentSizeEnum::M),
+            ("l", DeploymentSizeEnum::L),
+            ("Large", DeploymentSizeEnum::L),
+            ("xL", DeploymentSizeEnum::XL),
+            ("extra-large", DeploymentSizeEnum::XL),
+        ] {
+            assert_eq!(parse_size(input), Ok(size), "parsing {:?}", input);
+        }
+        let err = parse_size("huge").unwrap_err();
+        assert!(err.starts_with("Invalid size `huge`. Valid sizes are XS, S, M, L, and XL"));
+    }
+
+    #[test]
+    fn test_validate_openapi() {
+        let schema = json!({
+            "openapi": "3.0.3",
+            "info": {"title": "API", "version": "1.0.0"},
+            "paths": {"/api/deployments/": {"get": {"responses": {"200": {}}}}},
+        });
+        assert_eq!(validate_openapi(&schema), Ok(()));
+        let schema = json!({
+            "openapi": "2.0",
+            "info": {"title": "API"},
+            "paths": {"api/deployments/": {"post": {}}},
+        });
+        assert_eq!(
+            validate_openapi(&schema),
+            Err(vec![
+                "unsupported OpenAPI version 2.0".to_string(),
+                "missing `info.version`".to_string(),
+                "path `api/deployments/` does not start with `/`".to_string(),
+                "operation `POST api/deployments/` has no responses".to_string(),
+            ])
+        );
+        assert_eq!(
+            validate_openapi(&json!([])),
+            Err(vec![
+                "missing `openapi` version".to_string(),
+                "missing `info.title`".to_string(),
+                "missing `info.version`".to_string(),
+                "missing `paths` object".to_string(),
+            ])
+        );
+    }
+    #[test]
+    fn test_flatten_json() {
+        let mut out = BTreeMap::new();
+        flatten_json(
+            "",
+            json!({"id": "a", "tls": {"ca": "x", "key": null}, "tags": [1], "meta": {}}),
+            &mut out,
+        );
+        assert_eq!(
+            out.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("id".to_string(), json!("a")),
+                ("meta".to_string(), json!({})),
+                ("tags".to_string(), json!([1])),
+                ("tls.ca".to_string(), json!("x")),
+                ("tls.key".to_string(), json!(null)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_fields() {
+        let a = json!({"id": "a", "size": "XS", "tls": {"ca": "x"}, "name": "n"});
+        let b = json!({"id": "b", "size": "XS", "tls": {"ca": "y"}, "status": "OK"});
+        let diffs = diff_fields(&a, &b).unwrap();
+        assert_eq!(
+            serde_json::to_value(&diffs).unwrap(),
+            json!([
+                {"field": "id", "a": "a", "b": "b"},
+                {"field": "name", "a": "n"},
+                {"field": "status", "b": "OK"},
+                {"field": "tls.ca", "a": "x", "b": "y"},
+            ])
+        );
+        assert!(diff_fields(&a, &a).unwrap().is_empty());
+    }
+    #[test]
+    fn test_versions_compatible() {
+        for (client, server, compatible) in [
+            ("1.2.3", "1.4.0", Some(true)),
+            ("1.2.3", "v1.0", Some(true)),
+            ("1.2.3", "2.0.0", Some(false)),
+            ("0.1.0", "0.1.7", Some(true)),
+            ("0.1.0", "0.2.0", Some(false)),
+            ("0.9.0", "1.0.0", Some(false)),
+            ("1.0.0", "0.9.0", Some(false)),
+            ("1.0.0", "1", None),
+            ("1.0.0", "latest", None),
+            ("", "1.0.0", None),
+        ] {
+            assert_eq!(
+                versions_compatible(client, server),
+                compatible,
+                "comparing {:?} and {:?}",
+                client,
+                server
+            );
+        }
+    }
+    #[test]
+    fn test_redact_secrets() {
+        let mut request = DeploymentRequest {
+            cloud_provider_region: Box::new(parse_cloud_region("aws:us-east-1").unwrap()),
+            name: Some("n".to_string()),
+            size: None,
+            storage_mb: None,
+            disable_user_indexes: None,
+            materialized_extra_args: None,
+            mz_version: None,
+            enable_tailscale: Some(true),
+            tailscale_auth_key: Some("tskey-123".to_string()),
+        };
+        let body = redact_secrets(&request).unwrap();
+        assert_eq!(body["tailscaleAuthKey"], "<redacted>");
+        assert!(!body.to_string().contains("tskey-123"));
+
+        request.tailscale_auth_key = None;
+        assert_eq!(
+            redact_secrets(&request).unwrap(),
+            serde_json::to_value(&request).unwrap()
+        );
     }
 }
@@ -0,0 +1,190 @@
+// Copyright Materialize, Inc. and contributors. All rights reserved.
+//
+// Use of this software is governed by the Business Source License
+// included in the LICENSE file.
+//
+// As of the Change Date specified in that file, in accordance with
+// the Business Source License, use of this software will be governed
+// by the Apache License, Version 2.0.
+
+//! Information about the images (executables and shared libraries) mapped
+//! into a process, for symbolicating its addresses elsewhere.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The images mapped into a process.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappedImages {
+    pub images: Vec<MappedImage>,
+}
+
+/// An image mapped into a process.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappedImage {
+    /// The path of the image's file.
+    pub path: String,
+    /// The difference between the addresses at which the image is mapped and
+    /// the addresses in its file.
+    pub load_address: usize,
+    /// The image's GNU build ID, in hex, if it has one.
+    pub build_id: Option<String>,
+}
+
+impl fmt::Display for MappedImage {
+    /// Formats the image as its load address in hex, its build ID (or `-` if
+    /// it has none), and its path, separated by spaces.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} {} {}",
+            self.load_address,
+            self.build_id.as_deref().unwrap_or("-"),
+            self.path
+        )
+    }
+}
+
+impl MappedImages {
+    /// Returns the images currently mapped into this process.
+    ///
+    /// This is only supported on Linux; on other platforms, no images are
+    /// returned.
+    pub fn capture() -> MappedImages {
+        #[cfg(target_os = "linux")]
+        return linux::capture();
+        #[cfg(not(target_os = "linux"))]
+        return MappedImages::default();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::{c_void, CStr};
+    use std::os::raw::c_int;
+    use std::slice;
+
+    use super::{MappedImage, MappedImages};
+
+    /// The type of an ELF note that contains a GNU build ID.
+    const NT_GNU_BUILD_ID: u32 = 3;
+
+    pub fn capture() -> MappedImages {
+        let mut images = vec![];
+        // SAFETY: `callback` only accesses `data` as the `Vec` passed here,
+        // which outlives the call.
+        unsafe {
+            libc::dl_iterate_phdr(Some(callback), &mut images as *mut _ as *mut c_void);
+        }
+        MappedImages { images }
+    }
+
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut c_void,
+    ) -> c_int {
+        let images = &mut *(data as *mut Vec<MappedImage>);
+        let info = &*info;
+        let name = if info.dlpi_name.is_null() {
+            ""
+        } else {
+            CStr::from_ptr(info.dlpi_name).to_str().unwrap_or("")
+        };
+        // The main executable is reported first, without a name.
+        let path = if name.is_empty() && images.is_empty() {
+            std::env::current_exe()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        } else {
+            name.to_string()
+        };
+        let load_address = info.dlpi_addr as usize;
+        let phdrs = if info.dlpi_phdr.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(info.dlpi_phdr, usize::from(info.dlpi_phnum))
+        };
+        let build_id = phdrs
+            .iter()
+            .filter(|phdr| phdr.p_type == libc::PT_NOTE)
+            .find_map(|phdr| {
+                let start = load_address + phdr.p_vaddr as usize;
+                let notes = slice::from_raw_parts(start as *const u8, phdr.p_memsz as usize);
+                find_build_id(notes)
+            });
+        images.push(MappedImage {
+            path,
+            load_address,
+            build_id,
+        });
+        0
+    }
+
+    /// Finds the GNU build ID among the ELF notes in `notes`, and returns it
+    /// in hex.
+    fn find_build_id(mut notes: &[u8]) -> Option<String> {
+        let align = |n: usize| (n + 3) & !3;
+        let read_u32 = |bytes: &[u8]| u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        while notes.len() >= 12 {
+            let name_size = read_u32(&notes[0..4]) as usize;
+            let desc_size = read_u32(&notes[4..8]) as usize;
+            let note_type = read_u32(&notes[8..12]);
+            let name_start = 12;
+            let desc_start = name_start + align(name_size);
+            let next = desc_start + align(desc_size);
+            if next > notes.len() {
+                return None;
+            }
+            let name = &notes[name_start..name_start + name_size];
+            if note_type == NT_GNU_BUILD_ID && name == b"GNU\0" {
+                let desc = &notes[desc_start..desc_start + desc_size];
+                return Some(desc.iter().map(|b| format!("{:02x}", b)).collect());
+            }
+            notes = &notes[next..];
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{capture, find_build_id, MappedImages};
+
+        #[test]
+        fn test_capture() {
+            let images = capture().images;
+            assert_eq!(
+                images[0].path,
+                std::env::current_exe().unwrap().display().to_string()
+            );
+            // The executable is mapped below its own code, at 0 unless it is
+            // position independent.
+            assert!(images[0].load_address <= capture as fn() -> MappedImages as usize);
+            // Whether the executable has a build ID depends on the linker.
+            if let Some(build_id) = &images[0].build_id {
+                assert!(!build_id.is_empty());
+                assert!(build_id.chars().all(|c| c.is_ascii_hexdigit()));
+            }
+        }
+
+        #[test]
+        fn test_find_build_id() {
+            let mut notes = vec![];
+            // A note of another type, with a name that needs padding.
+            notes.extend(5u32.to_ne_bytes());
+            notes.extend(4u32.to_ne_bytes());
+            notes.extend(1u32.to_ne_bytes());
+            notes.extend(b"GNU\0\0\0\0\0");
+            notes.extend([0; 4]);
+            // The build ID.
+            notes.extend(4u32.to_ne_bytes());
+            notes.extend(3u32.to_ne_bytes());
+            notes.extend(3u32.to_ne_bytes());
+            notes.extend(b"GNU\0");
+            notes.extend([0xab, 0xcd, 0xef, 0]);
+            assert_eq!(find_build_id(&notes).as_deref(), Some("abcdef"));
+            assert_eq!(find_build_id(&notes[..20]), None);
+        }
+    }
+}
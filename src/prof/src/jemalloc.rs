@@ -14,11 +14,17 @@
 
 use std::os::unix::ffi::OsStrExt;
 use std::sync::Arc;
-use std::{ffi::CString, io::BufRead, time::Instant};
+use std::{
+    ffi::CString,
+    io::{BufRead, BufReader},
+    path::Path,
+    time::Instant,
+};
 use tokio::sync::Mutex;
 
 use anyhow::bail;
 use lazy_static::lazy_static;
+use serde::Serialize;
 use tempfile::NamedTempFile;
 use tikv_jemalloc_ctl::{epoch, raw, stats};
 
@@ -49,8 +55,63 @@ pub struct JemallocProfCtl {
     md: JemallocProfMetadata,
 }
 
+/// Returns the jemalloc size class that an allocation of `size` bytes is
+/// rounded up to.
+///
+/// This mirrors the default size class layout on 64-bit platforms (a 16-byte
+/// quantum, with four size classes per doubling above 64 bytes), e.g.
+/// 8, 16, 32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, ...
+/// See the "Size classes" table in http://jemalloc.net/jemalloc.3.html.
+pub fn size_class(size: usize) -> usize {
+    const QUANTUM: usize = 16;
+    if size <= 8 {
+        8
+    } else if size <= 4 * QUANTUM {
+        (size + QUANTUM - 1) / QUANTUM * QUANTUM
+    } else {
+        // Each doubling `(2^k, 2^(k+1)]` is split into four equally-spaced classes.
+        let lg_floor = usize::BITS - 1 - (size - 1).leading_zeros();
+        let step = 1usize << (lg_floor - 2);
+        (size + step - 1) / step * step
+    }
+}
+
+/// Options controlling how [`parse_jeheap`] interprets a heap profile.
+#[derive(Copy, Clone, Debug)]
+pub struct JeheapOptions {
+    /// Whether to annotate each stack with the jemalloc size class of its
+    /// allocations (see [`size_class`]).
+    ///
+    /// The heap profile format only reports the total number of objects and
+    /// bytes per stack, so the size class is derived from the mean allocation
+    /// size; stacks that allocate objects of very different sizes will be
+    /// attributed to a single class.
+    pub annotate_size_classes: bool,
+    /// Whether to correct for sampling, so that weights estimate the true
+    /// number of live bytes rather than the number of sampled bytes.
+    ///
+    /// jemalloc samples on average one allocation per `2^lg_prof_sample`
+    /// bytes, so an allocation of `size` bytes is sampled with probability
+    /// `1 - exp(-size / 2^lg_prof_sample)`; each sample is scaled by the
+    /// inverse of that probability. The sampling interval is read from the
+    /// header of the heap profile, so it reflects the value of
+    /// `lg_prof_sample` at the time the profile was dumped.
+    ///
+    /// If unset, weights are the raw sampled byte counts reported by jemalloc.
+    pub unbias: bool,
+}
+
+impl Default for JeheapOptions {
+    fn default() -> Self {
+        Self {
+            annotate_size_classes: false,
+            unbias: true,
+        }
+    }
+}
+
 /// Parse a jemalloc profile file, producing a vector of stack traces along with their weights.
-pub fn parse_jeheap<R: BufRead>(r: R) -> anyhow::Result<StackProfile> {
+pub fn parse_jeheap<R: BufRead>(r: R, options: JeheapOptions) -> anyhow::Result<StackProfile> {
     let mut cur_stack = None;
     let mut profile = <StackProfile as Default>::default();
     let mut lines = r.lines();
@@ -107,10 +168,20 @@ pub fn parse_jeheap<R: BufRead>(r: R) -> anyhow::Result<StackProfile> {
                 // And this gitter conversation between me (Brennan Vincent) and David Goldblatt: https://gitter.im/jemalloc/jemalloc?at=5f31b673811d3571b3bb9b6b
                 let n_objs = str::parse::<usize>(words[1].trim_end_matches(':'))? as f64;
                 let bytes_in_sampled_objs = str::parse::<usize>(words[2])? as f64;
-                let ratio = (bytes_in_sampled_objs / n_objs) / sampling_rate;
-                let scale_factor = 1.0 / (1.0 - (-ratio).exp());
-                let weight = bytes_in_sampled_objs * scale_factor;
-                profile.push(WeightedStack { addrs, weight }, None);
+                let weight = if options.unbias {
+                    let ratio = (bytes_in_sampled_objs / n_objs) / sampling_rate;
+                    let scale_factor = 1.0 / (1.0 - (-ratio).exp());
+                    bytes_in_sampled_objs * scale_factor
+                } else {
+                    bytes_in_sampled_objs
+                };
+                let anno = if options.annotate_size_classes && n_objs > 0.0 {
+                    let mean_size = (bytes_in_sampled_objs / n_objs).ceil() as usize;
+                    Some(format!("size class {}", size_class(mean_size)))
+                } else {
+                    None
+                };
+                profile.push(WeightedStack { addrs, weight }, anno.as_deref());
             }
         }
     }
@@ -120,15 +191,109 @@ pub fn parse_jeheap<R: BufRead>(r: R) -> anyhow::Result<StackProfile> {
     Ok(profile)
 }
 
+/// Activates jemalloc heap profiling, so that allocations are sampled from
+/// now on.
+///
+/// Unlike [`JemallocProfCtl::activate`], this does not record when profiling
+/// was activated. Fails if jemalloc does not support heap profiling.
+pub fn activate() -> anyhow::Result<()> {
+    set_active(true)
+}
+
+/// Deactivates jemalloc heap profiling.
+///
+/// Fails if jemalloc does not support heap profiling.
+pub fn deactivate() -> anyhow::Result<()> {
+    set_active(false)
+}
+
+/// Returns whether jemalloc heap profiling is active.
+///
+/// Fails if jemalloc does not support heap profiling.
+pub fn is_active() -> anyhow::Result<bool> {
+    ensure_prof_enabled()?;
+    // SAFETY: "prof.active" is documented as being readable and returning a bool:
+    // http://jemalloc.net/jemalloc.3.html#prof.active
+    Ok(unsafe { raw::read(b"prof.active\0") }?)
+}
+
+/// Writes a heap profile of the allocations sampled so far to `path`, in
+/// jemalloc's heap file format.
+///
+/// Fails if heap profiling is not active.
+pub fn dump(path: &Path) -> anyhow::Result<()> {
+    if !is_active()? {
+        bail!("jemalloc heap profiling is not active");
+    }
+    write_dump(path)
+}
+
+/// Dumps a heap profile as in [`dump`], and parses it with the default
+/// [`JeheapOptions`], so that each stack is weighted by its estimated number
+/// of live bytes.
+pub fn dump_to_stack_profile() -> anyhow::Result<StackProfile> {
+    let f = NamedTempFile::new()?;
+    dump(f.path())?;
+    parse_jeheap(BufReader::new(f), JeheapOptions::default())
+}
+
+fn write_dump(path: &Path) -> anyhow::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: "prof.dump" is documented as being writable and taking a C string as input:
+    // http://jemalloc.net/jemalloc.3.html#prof.dump
+    unsafe { raw::write(b"prof.dump\0", path.as_ptr()) }?;
+    Ok(())
+}
+
+fn set_active(active: bool) -> anyhow::Result<()> {
+    ensure_prof_enabled()?;
+    // SAFETY: "prof.active" is documented as being writable and taking a bool:
+    // http://jemalloc.net/jemalloc.3.html#prof.active
+    unsafe { raw::write(b"prof.active\0", active) }?;
+    Ok(())
+}
+
+/// Fails with a descriptive error if heap profiling is unavailable, either
+/// because jemalloc was built without it or because it was disabled at
+/// startup.
+fn ensure_prof_enabled() -> anyhow::Result<()> {
+    // SAFETY: "opt.prof" is documented as being readable and returning a bool:
+    // http://jemalloc.net/jemalloc.3.html#opt.prof
+    // It only exists if jemalloc was built with `--enable-prof`.
+    match unsafe { raw::read::<bool>(b"opt.prof\0") } {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("jemalloc heap profiling was disabled at startup (`opt.prof` is false)"),
+        Err(_) => bail!(
+            "jemalloc heap profiling is unsupported: jemalloc was not built with `--enable-prof`"
+        ),
+    }
+}
+
 // See stats.{allocated, active, ...} in http://jemalloc.net/jemalloc.3.html for details
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct JemallocStats {
     pub active: usize,
     pub allocated: usize,
+    pub mapped: usize,
     pub metadata: usize,
     pub resident: usize,
     pub retained: usize,
 }
 
+/// Returns jemalloc's current allocator statistics.
+pub fn stats() -> anyhow::Result<JemallocStats> {
+    // The statistics are cached, and only refreshed when the epoch advances.
+    epoch::advance()?;
+    Ok(JemallocStats {
+        active: stats::active::read()?,
+        allocated: stats::allocated::read()?,
+        mapped: stats::mapped::read()?,
+        metadata: stats::metadata::read()?,
+        resident: stats::resident::read()?,
+        retained: stats::retained::read()?,
+    })
+}
+
 impl JemallocProfCtl {
     // Creates and returns the global singleton.
     fn get() -> Option<Self> {
@@ -155,31 +320,23 @@ impl JemallocProfCtl {
         self.md
     }
 
-    pub fn activate(&mut self) -> Result<(), tikv_jemalloc_ctl::Error> {
-        // SAFETY: "prof.active" is documented as being writable and taking a bool:
-        // http://jemalloc.net/jemalloc.3.html#prof.active
-        unsafe { raw::write(b"prof.active\0", true) }?;
+    pub fn activate(&mut self) -> anyhow::Result<()> {
+        activate()?;
         if self.md.start_time.is_none() {
             self.md.start_time = Some(ProfStartTime::Instant(Instant::now()));
         }
         Ok(())
     }
 
-    pub fn deactivate(&mut self) -> Result<(), tikv_jemalloc_ctl::Error> {
-        // SAFETY: "prof.active" is documented as being writable and taking a bool:
-        // http://jemalloc.net/jemalloc.3.html#prof.active
-        unsafe { raw::write(b"prof.active\0", false) }?;
+    pub fn deactivate(&mut self) -> anyhow::Result<()> {
+        deactivate()?;
         self.md.start_time = None;
         Ok(())
     }
 
     pub fn dump(&mut self) -> anyhow::Result<std::fs::File> {
         let f = NamedTempFile::new()?;
-        let path = CString::new(f.path().as_os_str().as_bytes().to_vec()).unwrap();
-
-        // SAFETY: "prof.dump" is documented as being writable and taking a C string as input:
-        // http://jemalloc.net/jemalloc.3.html#prof.dump
-        unsafe { raw::write(b"prof.dump\0", path.as_ptr()) }?;
+        write_dump(f.path())?;
         Ok(f.into_file())
     }
 
@@ -193,13 +350,31 @@ impl JemallocProfCtl {
     }
 
     pub fn stats(&self) -> anyhow::Result<JemallocStats> {
-        epoch::advance()?;
-        Ok(JemallocStats {
-            active: stats::active::read()?,
-            allocated: stats::allocated::read()?,
-            metadata: stats::metadata::read()?,
-            resident: stats::resident::read()?,
-            retained: stats::retained::read()?,
-        })
+        stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::size_class;
+
+    #[test]
+    fn test_size_class() {
+        let cases = [
+            (1, 8),
+            (8, 8),
+            (9, 16),
+            (17, 32),
+            (33, 48),
+            (64, 64),
+            (65, 80),
+            (128, 128),
+            (129, 160),
+            (257, 320),
+            (4097, 5120),
+        ];
+        for (size, expected) in cases {
+            assert_eq!(size_class(size), expected, "size {}", size);
+        }
     }
 }
@@ -7,14 +7,36 @@
 // the Business Source License, use of this software will be governed
 // by the Apache License, Version 2.0.
 
-use serde::Serialize;
-use std::{collections::HashMap, ffi::c_void, time::Instant};
+use flate2::{write::GzEncoder, Compression};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::c_void,
+    fmt::{self, Write as _},
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use crate::images::MappedImages;
+
+pub mod images;
 #[cfg(feature = "jemalloc")]
 pub mod jemalloc;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(feature = "svg")]
+pub mod svg;
 pub mod time;
 
-#[derive(Copy, Clone, Debug)]
+/// The time at which profiling started.
+///
+/// Since an [`Instant`] is only meaningful within the process that created
+/// it, this is serialized as the time elapsed since profiling started, and is
+/// deserialized relative to the time of deserialization.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "ProfStartTimeRepr", from = "ProfStartTimeRepr")]
 // These constructors are dead on macOS
 #[allow(dead_code)]
 pub enum ProfStartTime {
@@ -22,17 +44,186 @@ pub enum ProfStartTime {
     TimeImmemorial,
 }
 
-#[derive(Clone, Debug)]
+impl ProfStartTime {
+    /// Returns how long profiling has been running, or `None` if it has been
+    /// running since the process started.
+    pub fn elapsed(&self) -> Option<Duration> {
+        match self {
+            ProfStartTime::Instant(instant) => Some(instant.elapsed()),
+            ProfStartTime::TimeImmemorial => None,
+        }
+    }
+}
+
+impl fmt::Display for ProfStartTime {
+    /// Formats how long profiling has been running, e.g. `for 1.5s` or `since
+    /// process start`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.elapsed() {
+            Some(elapsed) => write!(f, "for {:?}", elapsed),
+            None => f.write_str("since process start"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum ProfStartTimeRepr {
+    Elapsed(Duration),
+    TimeImmemorial,
+}
+
+impl From<ProfStartTime> for ProfStartTimeRepr {
+    fn from(time: ProfStartTime) -> Self {
+        match time {
+            ProfStartTime::Instant(instant) => ProfStartTimeRepr::Elapsed(instant.elapsed()),
+            ProfStartTime::TimeImmemorial => ProfStartTimeRepr::TimeImmemorial,
+        }
+    }
+}
+
+impl From<ProfStartTimeRepr> for ProfStartTime {
+    fn from(repr: ProfStartTimeRepr) -> Self {
+        match repr {
+            ProfStartTimeRepr::Elapsed(elapsed) => match Instant::now().checked_sub(elapsed) {
+                Some(instant) => ProfStartTime::Instant(instant),
+                None => ProfStartTime::TimeImmemorial,
+            },
+            ProfStartTimeRepr::TimeImmemorial => ProfStartTime::TimeImmemorial,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WeightedStack {
     pub addrs: Vec<usize>,
+    /// The weight of the stack, e.g. a number of bytes or samples.
+    ///
+    /// Integral weights, such as sample counts, are only represented exactly
+    /// up to 2^53; see [`WeightedStack::weight_is_integral`].
     pub weight: f64,
 }
 
-#[derive(Default)]
+/// The largest integer such that it and every smaller integer can be
+/// represented exactly by an `f64`.
+const MAX_EXACT_INTEGER: f64 = 9007199254740992.0; // 2^53
+
+impl WeightedStack {
+    /// Returns a stack weighted by a count, e.g. of samples.
+    ///
+    /// Counts greater than 2^53 are rounded to the nearest `f64`.
+    pub fn from_count(addrs: Vec<usize>, count: u64) -> WeightedStack {
+        WeightedStack {
+            addrs,
+            weight: count as f64,
+        }
+    }
+
+    /// Returns whether the weight of the stack is an integer that is
+    /// represented exactly, so that exporters can emit it as an integer
+    /// without losing precision.
+    pub fn weight_is_integral(&self) -> bool {
+        self.weight.fract() == 0.0 && self.weight.abs() <= MAX_EXACT_INTEGER
+    }
+}
+
+/// Metadata about where a stack was captured.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct StackMeta {
+    /// The ID of the thread on which the stack was captured, if known.
+    pub thread_id: Option<u64>,
+    /// The name of the thread on which the stack was captured, if known.
+    pub thread_name: Option<String>,
+}
+
+/// A collection of weighted stacks, each with an optional annotation and
+/// optional metadata.
+///
+/// A profile is serialized as a list of `(addrs, weight, annotation)` tuples,
+/// along with the metadata of each stack that has any, and any symbols for
+/// addresses that do not belong to this process (see
+/// [`StackProfile::from_collapsed`]).
+#[derive(Clone, Debug, Default)]
 pub struct StackProfile {
     annotations: Vec<String>,
+    // The index in `annotations` of each annotation.
+    annotation_idxs: HashMap<String, usize>,
     // The second element is the index in `annotations`, if one exists.
     stacks: Vec<(WeightedStack, Option<usize>)>,
+    metas: Vec<StackMeta>,
+    // The index in `metas` of each metadata.
+    meta_idxs: HashMap<StackMeta, usize>,
+    // The index in `metas` of the metadata of each stack, if it has any.
+    // Parallel to `stacks`.
+    stack_metas: Vec<Option<usize>>,
+    // Symbols for addresses that do not belong to this process, e.g. the
+    // synthetic addresses of a profile parsed from its collapsed form. These
+    // take precedence over the symbols of this process in `symbolicate`.
+    symbols: HashMap<usize, Vec<String>>,
+    // The images mapped into the profiled process, if known.
+    images: Option<MappedImages>,
+}
+
+/// An error encountered while parsing a profile in
+/// [`StackProfile::from_collapsed`] or [`StackProfile::from_mzfg`].
+#[derive(Debug)]
+pub struct ParseError {
+    /// The line on which the error occurred, starting at 1.
+    pub line: usize,
+    /// A description of the error.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An error encountered while writing a profile in
+/// [`StackProfile::write_mzfg`].
+#[derive(Debug)]
+pub enum MzfgError {
+    /// An entry of the extra headers whose key contains a colon, or whose key
+    /// or value contains a newline.
+    InvalidHeader { key: String },
+    /// An error writing the output.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MzfgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MzfgError::InvalidHeader { key } => write!(f, "invalid mzfg header: {:?}", key),
+            MzfgError::Io(e) => write!(f, "error writing mzfg: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MzfgError {}
+
+impl From<std::io::Error> for MzfgError {
+    fn from(e: std::io::Error) -> Self {
+        MzfgError::Io(e)
+    }
+}
+
+/// The headline numbers of a [`StackProfile`], as returned by
+/// [`StackProfile::summary`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ProfileSummary {
+    pub total_weight: f64,
+    pub stack_count: usize,
+    /// The heaviest leaf symbols, in descending order of weight.
+    pub top_leaves: Vec<LeafWeight>,
+}
+
+/// The total weight attributed to a leaf symbol.
+#[derive(Clone, Debug, Serialize)]
+pub struct LeafWeight {
+    pub name: String,
+    pub weight: f64,
 }
 
 pub struct StackProfileIter<'a> {
@@ -51,22 +242,123 @@ impl<'a> Iterator for StackProfileIter<'a> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct StackProfileRepr {
+    stacks: Vec<(Vec<usize>, f64, Option<String>)>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    symbols: BTreeMap<usize, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    images: Option<MappedImages>,
+    // The metadata of each stack that has any, by the index of the stack.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    metas: Vec<(usize, StackMeta)>,
+}
+
+impl Serialize for StackProfile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = StackProfileRepr {
+            stacks: self
+                .iter()
+                .map(|(stack, annotation)| {
+                    (
+                        stack.addrs.clone(),
+                        stack.weight,
+                        annotation.map(str::to_string),
+                    )
+                })
+                .collect(),
+            symbols: self
+                .symbols
+                .iter()
+                .map(|(addr, syms)| (*addr, syms.clone()))
+                .collect(),
+            images: self.images.clone(),
+            metas: self
+                .iter_with_meta()
+                .enumerate()
+                .filter_map(|(idx, (_stack, _annotation, meta))| Some((idx, meta?.clone())))
+                .collect(),
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StackProfile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = StackProfileRepr::deserialize(deserializer)?;
+        let mut profile = StackProfile {
+            symbols: repr.symbols.into_iter().collect(),
+            images: repr.images,
+            ..StackProfile::default()
+        };
+        let mut metas: HashMap<_, _> = repr.metas.into_iter().collect();
+        for (idx, (addrs, weight, annotation)) in repr.stacks.into_iter().enumerate() {
+            let stack = WeightedStack { addrs, weight };
+            match metas.remove(&idx) {
+                Some(meta) => profile.push_with_meta(stack, annotation.as_deref(), meta),
+                None => profile.push(stack, annotation.as_deref()),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl FromIterator<StackProfile> for StackProfile {
+    fn from_iter<I: IntoIterator<Item = StackProfile>>(iter: I) -> Self {
+        let mut merged = StackProfile::default();
+        for profile in iter {
+            merged.merge(&profile);
+        }
+        merged
+    }
+}
+
 impl StackProfile {
     pub fn push(&mut self, stack: WeightedStack, annotation: Option<&str>) {
-        let anno_idx = if let Some(annotation) = annotation {
-            Some(
-                self.annotations
-                    .iter()
-                    .position(|anno| annotation == anno.as_str())
-                    .unwrap_or_else(|| {
-                        self.annotations.push(annotation.to_string());
-                        self.annotations.len() - 1
-                    }),
-            )
-        } else {
-            None
+        let anno_idx = annotation.map(|annotation| match self.annotation_idxs.get(annotation) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.annotations.len();
+                self.annotations.push(annotation.to_string());
+                self.annotation_idxs.insert(annotation.to_string(), idx);
+                idx
+            }
+        });
+        self.stacks.push((stack, anno_idx));
+        self.stack_metas.push(None);
+    }
+
+    /// Like [`StackProfile::push`], but also records metadata about where the
+    /// stack was captured.
+    pub fn push_with_meta(
+        &mut self,
+        stack: WeightedStack,
+        annotation: Option<&str>,
+        meta: StackMeta,
+    ) {
+        let meta_idx = match self.meta_idxs.get(&meta) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.metas.len();
+                self.metas.push(meta.clone());
+                self.meta_idxs.insert(meta, idx);
+                idx
+            }
         };
-        self.stacks.push((stack, anno_idx))
+        self.push(stack, annotation);
+        *self.stack_metas.last_mut().unwrap() = Some(meta_idx);
+    }
+
+    /// Like [`StackProfile::iter`], but also yields the metadata of each
+    /// stack, if it has any.
+    pub fn iter_with_meta(
+        &self,
+    ) -> impl Iterator<Item = (&WeightedStack, Option<&str>, Option<&StackMeta>)> {
+        self.iter()
+            .zip(&self.stack_metas)
+            .map(move |((stack, annotation), meta)| {
+                (stack, annotation, meta.map(|idx| &self.metas[idx]))
+            })
     }
     pub fn iter(&self) -> StackProfileIter<'_> {
         StackProfileIter {
@@ -74,6 +366,977 @@ impl StackProfile {
             idx: 0,
         }
     }
+
+    /// Returns the number of stacks in the profile.
+    pub fn stack_count(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Returns whether the profile contains no stacks.
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Returns the number of distinct annotations in the profile.
+    pub fn annotation_count(&self) -> usize {
+        self.annotations.len()
+    }
+
+    /// Attaches information about the images mapped into the profiled process,
+    /// so that the profile can be symbolicated elsewhere.
+    ///
+    /// The images are recorded in the header of the profile's `mzfg` output.
+    pub fn with_images(self, images: MappedImages) -> StackProfile {
+        StackProfile {
+            images: Some(images),
+            ..self
+        }
+    }
+
+    /// Like [`StackProfile::iter`], but yields copies of the stacks with their
+    /// addresses reversed, i.e., ordered from the innermost frame to the
+    /// outermost.
+    pub fn iter_reversed(&self) -> impl Iterator<Item = (WeightedStack, Option<&str>)> {
+        self.iter().map(|(stack, annotation)| {
+            let stack = WeightedStack {
+                addrs: stack.addrs.iter().rev().cloned().collect(),
+                weight: stack.weight,
+            };
+            (stack, annotation)
+        })
+    }
+
+    /// Returns a profile containing the single stack captured in `bt`, with
+    /// the given weight and annotation.
+    ///
+    /// Like all stacks, the stack is ordered from the outermost frame to the
+    /// innermost, i.e., the reverse of the order of `bt`'s frames.
+    pub fn from_backtrace(
+        bt: &backtrace::Backtrace,
+        weight: f64,
+        annotation: Option<&str>,
+    ) -> StackProfile {
+        let addrs = bt
+            .frames()
+            .iter()
+            .rev()
+            .map(|frame| frame.ip() as usize)
+            .collect();
+        let mut profile = StackProfile::default();
+        profile.push(WeightedStack { addrs, weight }, annotation);
+        profile
+    }
+
+    /// Appends the stacks of `other` to this profile.
+    ///
+    /// Annotations of `other` are mapped to the equal annotations of this
+    /// profile, or added to it if it has none. Stacks are only concatenated:
+    /// stacks of `other` that are identical to stacks of this profile are
+    /// kept as separate stacks, rather than having their weights summed.
+    pub fn merge(&mut self, other: &StackProfile) {
+        // Synthetic addresses of `other` (see `from_collapsed`) may collide
+        // with those of this profile, so they are remapped by their symbols.
+        let mut addrs_by_symbols: HashMap<_, _> = self
+            .symbols
+            .iter()
+            .map(|(addr, syms)| (syms.clone(), *addr))
+            .collect();
+        let mut next_addr = self.symbols.keys().max().map_or(0, |addr| addr + 1);
+        let mut remap = HashMap::new();
+        let mut other_symbols: Vec<_> = other.symbols.iter().collect();
+        other_symbols.sort();
+        for (addr, syms) in other_symbols {
+            let new_addr = *addrs_by_symbols.entry(syms.clone()).or_insert_with(|| {
+                let new_addr = next_addr;
+                next_addr += 1;
+                self.symbols.insert(new_addr, syms.clone());
+                new_addr
+            });
+            remap.insert(*addr, new_addr);
+        }
+        for (stack, annotation, meta) in other.iter_with_meta() {
+            let stack = WeightedStack {
+                addrs: stack
+                    .addrs
+                    .iter()
+                    .map(|addr| *remap.get(addr).unwrap_or(addr))
+                    .collect(),
+                weight: stack.weight,
+            };
+            self.push_maybe_with_meta(stack, annotation, meta);
+        }
+    }
+
+    /// Returns the fraction of the total weight attributed to each leaf symbol.
+    ///
+    /// The leaf symbol of a stack is the innermost symbol of its innermost
+    /// address. Addresses that cannot be symbolicated are named by their hex
+    /// value. Symbols are looked up with [`symbolicate_cached`], so repeated
+    /// calls only resolve addresses that have not been seen before. Returns an
+    /// empty map if the total weight is zero.
+    pub fn leaf_shares(&self) -> BTreeMap<String, f64> {
+        let total = self.total_weight();
+        if total == 0.0 {
+            return BTreeMap::new();
+        }
+        let mut shares = self.leaf_weights();
+        for share in shares.values_mut() {
+            *share /= total;
+        }
+        shares
+    }
+
+    /// Returns the headline numbers of this profile: its total weight, its
+    /// number of stacks, and the `top` leaf symbols with the most weight.
+    ///
+    /// Leaf symbols are determined as in [`StackProfile::leaf_shares`].
+    pub fn summary(&self, top: usize) -> ProfileSummary {
+        let mut top_leaves: Vec<_> = self
+            .leaf_weights()
+            .into_iter()
+            .map(|(name, weight)| LeafWeight { name, weight })
+            .collect();
+        top_leaves.sort_by(|a, b| {
+            b.weight
+                .partial_cmp(&a.weight)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        top_leaves.truncate(top);
+        ProfileSummary {
+            total_weight: self.total_weight(),
+            stack_count: self.stacks.len(),
+            top_leaves,
+        }
+    }
+
+    /// Returns the weighted `q`-quantile of the depth of the stacks in this
+    /// profile, where each stack's depth is weighted by its weight.
+    ///
+    /// The depth of a stack is its number of addresses. Returns 0 if the
+    /// profile has no weight.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `q` is not between 0 and 1.
+    pub fn depth_quantile(&self, q: f64) -> usize {
+        assert!((0.0..=1.0).contains(&q), "quantile {} out of range", q);
+        let mut depths: Vec<_> = self
+            .stacks
+            .iter()
+            .map(|(stack, _)| (stack.addrs.len(), stack.weight))
+            .collect();
+        depths.sort_by_key(|(depth, _)| *depth);
+        let threshold = q * self.total_weight();
+        let mut cumulative = 0.0;
+        for (depth, weight) in depths {
+            cumulative += weight;
+            if weight > 0.0 && cumulative >= threshold {
+                return depth;
+            }
+        }
+        0
+    }
+
+    /// Renders this profile in the `mzfg` format.
+    ///
+    /// See [`StackProfile::write_mzfg`] for details.
+    pub fn to_mzfg(
+        &self,
+        symbolicate: bool,
+        header_extra: &[(&str, &str)],
+    ) -> Result<String, MzfgError> {
+        self.to_mzfg_opts(symbolicate, false, header_extra)
+    }
+
+    /// Like [`StackProfile::to_mzfg`], but with the options of
+    /// [`StackProfile::write_mzfg_opts`].
+    pub fn to_mzfg_opts(
+        &self,
+        symbolicate: bool,
+        mark_unresolved: bool,
+        header_extra: &[(&str, &str)],
+    ) -> Result<String, MzfgError> {
+        let mut buf = vec![];
+        self.write_mzfg_opts(&mut buf, symbolicate, mark_unresolved, header_extra)?;
+        // Everything written is valid UTF-8.
+        Ok(String::from_utf8(buf).unwrap())
+    }
+
+    /// Like [`StackProfile::to_mzfg`], but compresses the output with gzip.
+    ///
+    /// The decompressed output is byte-for-byte identical to that of
+    /// `to_mzfg`.
+    pub fn to_mzfg_gzip(
+        &self,
+        symbolicate: bool,
+        header_extra: &[(&str, &str)],
+    ) -> Result<Vec<u8>, MzfgError> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        self.write_mzfg(&mut encoder, symbolicate, header_extra)?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Like [`StackProfile::to_mzfg`], but for headers that are known to be
+    /// valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an entry of `header_extra` is invalid.
+    pub fn to_mzfg_unchecked(&self, symbolicate: bool, header_extra: &[(&str, &str)]) -> String {
+        match self.to_mzfg(symbolicate, header_extra) {
+            Ok(mzfg) => mzfg,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Writes this profile in the `mzfg` format to `w`.
+    ///
+    /// The output begins with a header of `key: value` lines, including those
+    /// in `header_extra`, terminated by a blank line. Then each stack is
+    /// written on its own line as its addresses in hex, outermost first and
+    /// each followed by a semicolon, then a space and the stack's weight, and
+    /// then a space and its annotation, if any.
+    ///
+    /// If `symbolicate` is set, the stacks are followed by a blank line and
+    /// then the symbols of each address, as the address in hex, a space, and
+    /// the symbols, outermost first and each followed by a semicolon. If the
+    /// module containing the address is known, its name follows the last
+    /// semicolon, after a space. Backslashes and semicolons in symbols and
+    /// module names are escaped with a backslash.
+    ///
+    /// Addresses that resolve to no symbols are omitted from the symbol
+    /// section, so that they are rendered as raw addresses.
+    ///
+    /// Fails without writing anything if a key in `header_extra` contains a
+    /// colon, or if a key or value contains a newline.
+    pub fn write_mzfg<W: Write>(
+        &self,
+        w: &mut W,
+        symbolicate: bool,
+        header_extra: &[(&str, &str)],
+    ) -> Result<(), MzfgError> {
+        self.write_mzfg_opts(w, symbolicate, false, header_extra)
+    }
+
+    /// Like [`StackProfile::write_mzfg`], but if `mark_unresolved` is set,
+    /// addresses that resolve to no symbols are written to the symbol section
+    /// with the single unknown symbol `???`, rather than omitted.
+    ///
+    /// This lets the viewer distinguish addresses whose symbolication failed
+    /// from addresses that were never symbolicated. It has no effect unless
+    /// `symbolicate` is set.
+    pub fn write_mzfg_opts<W: Write>(
+        &self,
+        w: &mut W,
+        symbolicate: bool,
+        mark_unresolved: bool,
+        header_extra: &[(&str, &str)],
+    ) -> Result<(), MzfgError> {
+        for (k, v) in header_extra {
+            if k.contains(':') || k.contains('\n') || v.contains('\n') {
+                return Err(MzfgError::InvalidHeader { key: k.to_string() });
+            }
+        }
+        writeln!(w, "mz_fg_version: 1")?;
+        for (k, v) in header_extra {
+            writeln!(w, "{}: {}", k, v)?;
+        }
+        if let Some(images) = &self.images {
+            for image in &images.images {
+                writeln!(w, "mapped_image: {}", image)?;
+            }
+        }
+        writeln!(w)?;
+
+        for (stack, annotation) in self.iter() {
+            for addr in &stack.addrs {
+                write!(w, "{:#x};", addr)?;
+            }
+            write!(w, " {}", stack.weight)?;
+            if let Some(annotation) = annotation {
+                write!(w, " {}", annotation)?;
+            }
+            writeln!(w)?;
+        }
+
+        if symbolicate {
+            // The reader splits on semicolons, so they must be escaped.
+            let escape = |s: &str| s.replace('\\', "\\\\").replace(';', "\\;");
+            writeln!(w)?;
+            for (addr, frames) in resolve(self, Demangle::Rust) {
+                if frames.is_empty() {
+                    if mark_unresolved {
+                        writeln!(w, "{:#x} ???;", addr)?;
+                    }
+                    continue;
+                }
+                write!(w, "{:#x} ", addr)?;
+                for frame in &frames {
+                    write!(w, "{};", escape(&frame.name))?;
+                }
+                if let Some(module) = &frames[0].module {
+                    write!(w, " {}", escape(module))?;
+                }
+                writeln!(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders this profile as a [Chrome trace], which can be loaded into
+    /// Perfetto or `chrome://tracing`.
+    ///
+    /// The stacks are laid end to end on a single synthetic timeline, each
+    /// lasting for its weight. Each frame of a stack, outermost first, becomes
+    /// a complete (`"ph": "X"`) event spanning the whole stack, so the events
+    /// of a stack nest according to its call chain. Frames are named by their
+    /// symbols, or in hex if they have none. A stack's annotation, if any, is
+    /// attached to its outermost event. The events of a stack belong to the
+    /// thread with the ID in the stack's metadata, if any, or else thread 0.
+    ///
+    /// [Chrome trace]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    pub fn to_chrome_trace(&self) -> String {
+        #[derive(Serialize)]
+        struct Event<'a> {
+            name: &'a str,
+            ph: &'static str,
+            ts: f64,
+            dur: f64,
+            pid: u32,
+            tid: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            args: Option<BTreeMap<&'static str, &'a str>>,
+        }
+
+        let frame_names = self.frame_names(true);
+        let mut events = vec![];
+        let mut ts = 0.0;
+        for (stack, annotation, meta) in self.iter_with_meta() {
+            let tid = meta.and_then(|meta| meta.thread_id).unwrap_or(0);
+            let names = stack.addrs.iter().flat_map(|addr| &frame_names[addr]);
+            for (i, name) in names.enumerate() {
+                let args = match annotation {
+                    Some(annotation) if i == 0 => {
+                        Some(std::iter::once(("annotation", annotation)).collect())
+                    }
+                    _ => None,
+                };
+                events.push(Event {
+                    name,
+                    ph: "X",
+                    ts,
+                    dur: stack.weight,
+                    pid: 0,
+                    tid,
+                    args,
+                });
+            }
+            ts += stack.weight;
+        }
+        serde_json::to_string(&events).expect("serialization cannot fail")
+    }
+
+    /// Renders this profile as a sampled profile in the [speedscope] file
+    /// format, named `name`.
+    ///
+    /// Each stack becomes a sample, weighted by the stack's weight, whose
+    /// frames are indices into a table of frames shared by all samples. Frames
+    /// are named as in [`StackProfile::to_collapsed`] with symbolication, and
+    /// as there, annotations are appended as a final `[anno=...]` frame.
+    ///
+    /// [speedscope]: https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+    pub fn to_speedscope(&self, name: &str) -> String {
+        let frame_names = self.frame_names(true);
+        let mut frames = vec![];
+        let mut frame_idxs = HashMap::new();
+        let mut frame_idx = |name: String| {
+            *frame_idxs.entry(name.clone()).or_insert_with(|| {
+                frames.push(serde_json::json!({ "name": name }));
+                frames.len() - 1
+            })
+        };
+        let mut samples = vec![];
+        let mut weights = vec![];
+        for (stack, annotation) in self.iter() {
+            let mut sample: Vec<_> = stack
+                .addrs
+                .iter()
+                .flat_map(|addr| &frame_names[addr])
+                .map(|name| frame_idx(name.clone()))
+                .collect();
+            if let Some(annotation) = annotation {
+                sample.push(frame_idx(format!("[anno={}]", annotation)));
+            }
+            samples.push(sample);
+            weights.push(stack.weight);
+        }
+        let profile = serde_json::json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "name": name,
+            "activeProfileIndex": 0,
+            "exporter": "materialize",
+            "shared": { "frames": frames },
+            "profiles": [{
+                "type": "sampled",
+                "name": name,
+                "unit": "none",
+                "startValue": 0.0,
+                "endValue": self.total_weight(),
+                "samples": samples,
+                "weights": weights,
+            }],
+        });
+        profile.to_string()
+    }
+
+    /// Renders this profile in the collapsed (or "folded") format understood
+    /// by Brendan Gregg's `flamegraph.pl` and compatible tools.
+    ///
+    /// Each stack is rendered on its own line as its frames, outermost first,
+    /// separated by semicolons, followed by a space and the stack's weight:
+    ///
+    /// ```text
+    /// main;foo;bar 30
+    /// main;foo;quux 40.5
+    /// ```
+    ///
+    /// If `symbolicate` is set, each address is replaced by its symbols (more
+    /// than one if there was inlining); addresses without symbols, or all
+    /// addresses if `symbolicate` is unset, are rendered in hex. If the stack
+    /// has an annotation, it is appended as a final frame of the form
+    /// `[anno=...]`. Weights are rendered as integers when they are whole.
+    pub fn to_collapsed(&self, symbolicate: bool) -> String {
+        let frame_names = self.frame_names(symbolicate);
+        let mut out = String::new();
+        for (stack, annotation) in self.iter() {
+            let mut frames: Vec<_> = stack
+                .addrs
+                .iter()
+                .flat_map(|addr| frame_names[addr].iter().cloned())
+                .collect();
+            if let Some(annotation) = annotation {
+                frames.push(format!("[anno={}]", annotation));
+            }
+            // `f64`'s `Display` implementation omits the fractional part of
+            // whole numbers.
+            writeln!(out, "{} {}", frames.join(";"), stack.weight).unwrap();
+        }
+        out
+    }
+
+    /// Returns the names of the frames of each address: its symbols if
+    /// `symbolicate` is set and it has any, or else its value in hex.
+    fn frame_names(&self, symbolicate: bool) -> HashMap<usize, Vec<String>> {
+        let mut names = if symbolicate {
+            crate::symbolicate(self)
+        } else {
+            HashMap::new()
+        };
+        for addr in unique_addrs(self) {
+            let syms = names.entry(addr).or_default();
+            if syms.is_empty() {
+                syms.push(format!("{:#x}", addr));
+            }
+        }
+        names
+    }
+
+    /// Encodes this profile as a gzipped [pprof] `Profile` protobuf message.
+    ///
+    /// Addresses are symbolicated, and each symbol becomes a `Function` (more
+    /// than one per `Location` if there was inlining), with its source file
+    /// and line if they are known. Each stack becomes a
+    /// `Sample` whose single value is the stack's weight, rounded to the
+    /// nearest integer and described by `sample_type`, which is a pair of type
+    /// and unit (e.g., `("space", "bytes")` for heap profiles). Annotations are
+    /// attached to samples as an `annotation` label, and the thread ID and name
+    /// of the stacks' metadata as `thread_id` and `thread_name` labels. If
+    /// `period` is provided, it is recorded as the sampling period, in units
+    /// of `sample_type`.
+    ///
+    /// Weights for which [`WeightedStack::weight_is_integral`] holds, such as
+    /// sample counts, are emitted exactly. If any weight is not, the profile
+    /// carries a comment saying that its values were rounded.
+    ///
+    /// [pprof]: https://github.com/google/pprof/blob/master/proto/profile.proto
+    pub fn to_pprof(&self, sample_type: (&str, &str), period: Option<u64>) -> Vec<u8> {
+        use pprof::protos::{self, Message};
+
+        let addr_to_frames = symbolicate_with_locations(self);
+
+        // The first string in the string table must be the empty string.
+        let mut strings = vec!["".to_string()];
+        let mut string_ids = HashMap::new();
+        string_ids.insert("".to_string(), 0);
+        let mut intern = |s: &str| -> i64 {
+            if let Some(id) = string_ids.get(s) {
+                return *id;
+            }
+            let id = strings.len() as i64;
+            strings.push(s.to_string());
+            string_ids.insert(s.to_string(), id);
+            id
+        };
+
+        let sample_type = protos::ValueType {
+            r#type: intern(sample_type.0),
+            unit: intern(sample_type.1),
+        };
+        let annotation_key = intern("annotation");
+        let thread_id_key = intern("thread_id");
+        let thread_name_key = intern("thread_name");
+
+        let mut functions = vec![];
+        let mut function_ids = HashMap::new();
+        let mut locations = vec![];
+        let mut location_ids = HashMap::new();
+        let mut samples = vec![];
+        let mut rounded = false;
+        for (stack, annotation, meta) in self.iter_with_meta() {
+            rounded |= !stack.weight_is_integral();
+            let mut location_id = vec![];
+            // pprof expects stacks to be ordered from the leaf to the root.
+            for addr in stack.addrs.iter().rev() {
+                let id = match location_ids.get(addr) {
+                    Some(id) => *id,
+                    None => {
+                        let mut lines = vec![];
+                        // pprof expects inlined functions to be ordered from
+                        // the innermost to the outermost.
+                        let frames = addr_to_frames.get(addr).map(Vec::as_slice).unwrap_or(&[]);
+                        for frame in frames.iter().rev() {
+                            let key = (&frame.name, &frame.filename);
+                            let function_id = match function_ids.get(&key) {
+                                Some(id) => *id,
+                                None => {
+                                    let id = functions.len() as u64 + 1;
+                                    let name = intern(&frame.name);
+                                    functions.push(protos::Function {
+                                        id,
+                                        name,
+                                        system_name: name,
+                                        filename: intern(frame.filename.as_deref().unwrap_or("")),
+                                        ..protos::Function::default()
+                                    });
+                                    function_ids.insert(key, id);
+                                    id
+                                }
+                            };
+                            lines.push(protos::Line {
+                                function_id,
+                                line: frame.lineno.map_or(0, i64::from),
+                            });
+                        }
+                        let id = locations.len() as u64 + 1;
+                        locations.push(protos::Location {
+                            id,
+                            address: *addr as u64,
+                            line: lines,
+                            ..protos::Location::default()
+                        });
+                        location_ids.insert(*addr, id);
+                        id
+                    }
+                };
+                location_id.push(id);
+            }
+            let mut label = vec![];
+            if let Some(annotation) = annotation {
+                label.push(protos::Label {
+                    key: annotation_key,
+                    str: intern(annotation),
+                    ..protos::Label::default()
+                });
+            }
+            if let Some(thread_id) = meta.and_then(|meta| meta.thread_id) {
+                label.push(protos::Label {
+                    key: thread_id_key,
+                    num: thread_id as i64,
+                    ..protos::Label::default()
+                });
+            }
+            if let Some(thread_name) = meta.and_then(|meta| meta.thread_name.as_deref()) {
+                label.push(protos::Label {
+                    key: thread_name_key,
+                    str: intern(thread_name),
+                    ..protos::Label::default()
+                });
+            }
+            samples.push(protos::Sample {
+                location_id,
+                value: vec![stack.weight.round() as i64],
+                label,
+            });
+        }
+
+        let mut comment = vec![];
+        if rounded {
+            comment.push(intern("sample values were rounded to the nearest integer"));
+        }
+
+        let profile = protos::Profile {
+            sample_type: vec![sample_type.clone()],
+            sample: samples,
+            location: locations,
+            function: functions,
+            string_table: strings,
+            period_type: period.map(|_| sample_type),
+            period: period.unwrap_or(0) as i64,
+            comment,
+            ..protos::Profile::default()
+        };
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        // Writing to a `Vec` never fails.
+        encoder.write_all(&profile.encode_to_vec()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Parses a profile in the collapsed format produced by
+    /// [`StackProfile::to_collapsed`].
+    ///
+    /// Each distinct frame name is assigned a synthetic address, in order of
+    /// first appearance, that symbolicates back to that name, so the profile
+    /// round-trips through `to_collapsed(true)`. A final frame of the form
+    /// `[anno=...]` is parsed as the stack's annotation. Blank lines are
+    /// ignored.
+    pub fn from_collapsed(input: &str) -> Result<StackProfile, ParseError> {
+        let mut profile = StackProfile::default();
+        let mut addrs_by_name = HashMap::new();
+        for (i, line) in input.lines().enumerate() {
+            let err = |message: String| ParseError {
+                line: i + 1,
+                message,
+            };
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let (frames, weight) = line
+                .rsplit_once(' ')
+                .ok_or_else(|| err("missing weight".to_string()))?;
+            let weight: f64 = weight
+                .parse()
+                .map_err(|_| err(format!("invalid weight: {}", weight)))?;
+            let mut frames: Vec<_> = if frames.is_empty() {
+                vec![]
+            } else {
+                frames.split(';').collect()
+            };
+            let annotation = match frames.last() {
+                Some(frame) if frame.starts_with("[anno=") && frame.ends_with(']') => {
+                    let annotation = &frame["[anno=".len()..frame.len() - 1];
+                    frames.pop();
+                    Some(annotation)
+                }
+                _ => None,
+            };
+            let mut addrs = vec![];
+            for frame in frames {
+                if frame.is_empty() {
+                    return Err(err("empty frame".to_string()));
+                }
+                let next_addr = addrs_by_name.len();
+                let addr = *addrs_by_name.entry(frame).or_insert(next_addr);
+                if addr == next_addr {
+                    profile.symbols.insert(addr, vec![frame.to_string()]);
+                }
+                addrs.push(addr);
+            }
+            profile.push(WeightedStack { addrs, weight }, annotation);
+        }
+        Ok(profile)
+    }
+
+    /// Parses a profile in the `mzfg` format produced by
+    /// [`StackProfile::write_mzfg`].
+    ///
+    /// The symbols in the symbol section, if any, are attached to the profile,
+    /// so that they take precedence over symbolicating its addresses in this
+    /// process. Module names in the symbol section and header lines other
+    /// than `mapped_image` are ignored.
+    pub fn from_mzfg(input: &str) -> Result<StackProfile, ParseError> {
+        let mut profile = StackProfile::default();
+        let mut lines = input.lines().enumerate();
+        let err = |i: usize, message: String| ParseError {
+            line: i + 1,
+            message,
+        };
+
+        let mut images = vec![];
+        let mut version = None;
+        for (i, line) in &mut lines {
+            if line.is_empty() {
+                break;
+            }
+            let (key, value) = line
+                .split_once(": ")
+                .ok_or_else(|| err(i, "invalid header line".to_string()))?;
+            match key {
+                "mz_fg_version" => version = Some((i, value)),
+                "mapped_image" => images.push(
+                    parse_mapped_image(value)
+                        .ok_or_else(|| err(i, format!("invalid mapped image: {}", value)))?,
+                ),
+                _ => (),
+            }
+        }
+        match version {
+            Some((_, "1")) => (),
+            Some((i, version)) => return Err(err(i, format!("unsupported version: {}", version))),
+            None => return Err(err(0, "missing version".to_string())),
+        }
+        if !images.is_empty() {
+            profile.images = Some(MappedImages { images });
+        }
+
+        for (i, line) in &mut lines {
+            if line.is_empty() {
+                break;
+            }
+            let (addrs, rest) = line
+                .split_once(' ')
+                .ok_or_else(|| err(i, "missing weight".to_string()))?;
+            let (weight, annotation) = match rest.split_once(' ') {
+                Some((weight, annotation)) => (weight, Some(annotation)),
+                None => (rest, None),
+            };
+            let weight: f64 = weight
+                .parse()
+                .map_err(|_| err(i, format!("invalid weight: {}", weight)))?;
+            let addrs = addrs
+                .split_terminator(';')
+                .map(|addr| {
+                    parse_hex(addr).ok_or_else(|| err(i, format!("invalid address: {}", addr)))
+                })
+                .collect::<Result<_, _>>()?;
+            profile.push(WeightedStack { addrs, weight }, annotation);
+        }
+
+        for (i, line) in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let (addr, rest) = line
+                .split_once(' ')
+                .ok_or_else(|| err(i, "missing symbols".to_string()))?;
+            let addr =
+                parse_hex(addr).ok_or_else(|| err(i, format!("invalid address: {}", addr)))?;
+            let mut names = vec![];
+            let mut name = String::new();
+            let mut chars = rest.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some(c @ ('\\' | ';')) => name.push(c),
+                        _ => return Err(err(i, "invalid escape sequence".to_string())),
+                    },
+                    ';' => names.push(std::mem::take(&mut name)),
+                    c => name.push(c),
+                }
+            }
+            // Anything after the last name is the module name.
+            if names.is_empty() {
+                return Err(err(i, "missing symbols".to_string()));
+            }
+            profile.symbols.insert(addr, names);
+        }
+        Ok(profile)
+    }
+
+    /// Returns a new profile containing only the stacks whose annotation
+    /// satisfies `pred`.
+    ///
+    /// Annotations that no remaining stack uses are dropped.
+    pub fn filter<F: Fn(Option<&str>) -> bool>(&self, pred: F) -> StackProfile {
+        self.select(|_idx, _stack, annotation| pred(annotation))
+    }
+
+    /// Retains only the stacks for which `f`, called with each stack and its
+    /// annotation, returns true, in their original order.
+    ///
+    /// Annotations that no remaining stack uses are dropped.
+    pub fn retain<F: FnMut(&WeightedStack, Option<&str>) -> bool>(&mut self, mut f: F) {
+        *self = self.select(|_idx, stack, annotation| f(stack, annotation));
+    }
+
+    /// Returns a new profile in which stacks with the same addresses,
+    /// annotation, and metadata are combined into a single stack, weighted by
+    /// the sum of their weights.
+    ///
+    /// The stacks of the new profile are ordered by their addresses, then by
+    /// their annotations, then by their metadata.
+    pub fn aggregate(&self) -> StackProfile {
+        let mut weights: BTreeMap<_, f64> = BTreeMap::new();
+        for (stack, annotation, meta) in self.iter_with_meta() {
+            *weights.entry((&stack.addrs, annotation, meta)).or_default() += stack.weight;
+        }
+        let mut aggregated = StackProfile {
+            symbols: self.symbols.clone(),
+            images: self.images.clone(),
+            ..StackProfile::default()
+        };
+        for ((addrs, annotation, meta), weight) in weights {
+            let addrs = addrs.clone();
+            aggregated.push_maybe_with_meta(WeightedStack { addrs, weight }, annotation, meta);
+        }
+        aggregated
+    }
+
+    /// Multiplies the weight of every stack by `factor`.
+    ///
+    /// Stacks are kept even if their weight becomes zero.
+    pub fn scale(&mut self, factor: f64) {
+        for (stack, _annotation) in &mut self.stacks {
+            stack.weight *= factor;
+        }
+    }
+
+    /// Like [`StackProfile::scale`], but returns a scaled copy of this
+    /// profile.
+    pub fn scaled(&self, factor: f64) -> StackProfile {
+        let mut scaled = self.clone();
+        scaled.scale(factor);
+        scaled
+    }
+
+    /// Returns a new profile containing only the `n` stacks with the highest
+    /// weight, in their original order.
+    ///
+    /// Among stacks of equal weight, those pushed earlier are preferred.
+    /// Annotations that no remaining stack uses are dropped.
+    pub fn top_n(&self, n: usize) -> StackProfile {
+        let mut idxs: Vec<_> = (0..self.stacks.len()).collect();
+        // `sort_by` is stable, so ties stay in insertion order.
+        idxs.sort_by(|a, b| {
+            let (a, b) = (&self.stacks[*a].0, &self.stacks[*b].0);
+            b.weight
+                .partial_cmp(&a.weight)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        idxs.truncate(n);
+        let keep: HashSet<_> = idxs.into_iter().collect();
+        self.select(|idx, _stack, _annotation| keep.contains(&idx))
+    }
+
+    /// Returns a new profile containing the stacks for which `pred`, called
+    /// with the index, stack, and annotation of each stack, returns true.
+    ///
+    /// The new profile's annotation table only contains the annotations of
+    /// the selected stacks.
+    fn select<F>(&self, mut pred: F) -> StackProfile
+    where
+        F: FnMut(usize, &WeightedStack, Option<&str>) -> bool,
+    {
+        let mut selected = StackProfile {
+            symbols: self.symbols.clone(),
+            images: self.images.clone(),
+            ..StackProfile::default()
+        };
+        for (idx, (stack, annotation, meta)) in self.iter_with_meta().enumerate() {
+            if pred(idx, stack, annotation) {
+                selected.push_maybe_with_meta(stack.clone(), annotation, meta);
+            }
+        }
+        selected
+    }
+
+    fn push_maybe_with_meta(
+        &mut self,
+        stack: WeightedStack,
+        annotation: Option<&str>,
+        meta: Option<&StackMeta>,
+    ) {
+        match meta {
+            Some(meta) => self.push_with_meta(stack, annotation, meta.clone()),
+            None => self.push(stack, annotation),
+        }
+    }
+
+    /// Computes the difference between two profiles, for rendering as a
+    /// differential flamegraph.
+    ///
+    /// The weights of identical stacks, i.e., stacks with the same addresses,
+    /// are summed within each profile, and the result contains one stack for
+    /// each distinct stack whose weight in `after` differs from its weight in
+    /// `before`, weighted by the difference. Weights may thus be negative.
+    /// Annotations and metadata are ignored, and the result has none.
+    pub fn diff(before: &StackProfile, after: &StackProfile) -> StackProfile {
+        // Map the synthetic addresses of `after`, if any, onto those of
+        // `before`.
+        let mut remapped = StackProfile {
+            symbols: before.symbols.clone(),
+            images: before.images.clone(),
+            ..StackProfile::default()
+        };
+        remapped.merge(after);
+
+        let mut order = vec![];
+        let mut weights = HashMap::new();
+        for (profile, sign) in [(before, -1.0), (&remapped, 1.0)] {
+            for (stack, _annotation) in profile.iter() {
+                let weight = weights.entry(&stack.addrs).or_insert_with(|| {
+                    order.push(&stack.addrs);
+                    0.0
+                });
+                *weight += sign * stack.weight;
+            }
+        }
+
+        let mut diff = StackProfile::default();
+        for addrs in order {
+            let weight = weights[addrs];
+            if weight != 0.0 {
+                let addrs = addrs.clone();
+                diff.push(WeightedStack { addrs, weight }, None);
+            }
+        }
+        diff.symbols = remapped.symbols;
+        diff.images = remapped.images;
+        diff
+    }
+
+    /// Returns the sum of the weights of all stacks.
+    pub fn total_weight(&self) -> f64 {
+        self.stacks.iter().map(|(stack, _)| stack.weight).sum()
+    }
+
+    /// Returns the sum of the weights of the stacks with each annotation.
+    ///
+    /// The weight of stacks without an annotation is reported under `None`.
+    pub fn weight_by_annotation(&self) -> BTreeMap<Option<&str>, f64> {
+        let mut weights = BTreeMap::new();
+        for (stack, annotation) in self.iter() {
+            *weights.entry(annotation).or_insert(0.0) += stack.weight;
+        }
+        weights
+    }
+
+    fn leaf_weights(&self) -> BTreeMap<String, f64> {
+        let mut weights = BTreeMap::new();
+        let addr_to_symbols = symbolicate_cached(self);
+        for (stack, _annotation) in &self.stacks {
+            let leaf = match stack.addrs.last() {
+                Some(addr) => match addr_to_symbols.get(addr).and_then(|syms| syms.last()) {
+                    Some(sym) => sym.clone(),
+                    None => format!("{:#x}", addr),
+                },
+                None => continue,
+            };
+            *weights.entry(leaf).or_insert(0.0) += stack.weight;
+        }
+        weights
+    }
+
+    /// Collates the stacks like [`collate_stacks`], but keeps at most
+    /// `max_depth` symbols of each stack.
+    ///
+    /// Rather than being dropped, the weight of the symbols beyond `max_depth`
+    /// is attributed to a synthetic `[deeper]` child of the last kept symbol,
+    /// so every node retains its full weight.
+    pub fn cap_depth_weighted(self, max_depth: usize) -> WeightedSymbolTrie {
+        collate_stacks_inner(self, Some(max_depth))
+    }
 }
 #[derive(Serialize)]
 pub struct SymbolTrieNode {
@@ -149,6 +1412,211 @@ impl WeightedSymbolTrie {
 /// Each address could correspond to more than one symbol, becuase
 /// of inlining. (E.g. if 0x1234 comes from "g", which is inlined in "f", the corresponding vec of symbols will be ["f", "g"].)
 pub fn symbolicate(profile: &StackProfile) -> HashMap<usize, Vec<String>> {
+    symbolicate_opts(profile, Demangle::Rust, false)
+}
+
+/// How to render symbol names during symbolication.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Demangle {
+    /// Leave symbol names mangled.
+    None,
+    /// Demangle Rust symbol names, keeping their hash suffix.
+    Rust,
+    /// Demangle Rust symbol names, and strip their hash suffix.
+    Full,
+}
+
+fn demangle(name: &backtrace::SymbolName<'_>, demangle: Demangle) -> String {
+    match demangle {
+        Demangle::None => String::from_utf8_lossy(name.as_bytes()).into_owned(),
+        Demangle::Rust => name.to_string(),
+        // The alternate form of a demangled name omits the hash.
+        Demangle::Full => format!("{:#}", name),
+    }
+}
+
+/// Like [`symbolicate`], but renders symbol names as specified by `demangle`.
+///
+/// Symbols that were not resolved from this process (see
+/// [`StackProfile::from_collapsed`]) are returned as is.
+///
+/// If `drop_unknown` is set, addresses that resolve to no symbols, or only to
+/// unknown (`???`) symbols, are omitted from the returned map, so that they
+/// are rendered as raw addresses. The stacks of the profile are unaffected.
+pub fn symbolicate_opts(
+    profile: &StackProfile,
+    demangle: Demangle,
+    drop_unknown: bool,
+) -> HashMap<usize, Vec<String>> {
+    resolve(profile, demangle)
+        .into_iter()
+        .filter(|(_addr, frames)| !drop_unknown || frames.iter().any(|frame| frame.name != "???"))
+        .map(|(addr, frames)| {
+            let names = frames.into_iter().map(|frame| frame.name).collect();
+            (addr, names)
+        })
+        .collect()
+}
+
+/// A symbol to which an address resolves, along with its location in the
+/// source code, if known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub name: String,
+    pub filename: Option<String>,
+    pub lineno: Option<u32>,
+    /// The name of the file of the executable or shared library containing
+    /// the symbol, without its directory (e.g., `libc.so.6`), if known.
+    pub module: Option<String>,
+}
+
+/// Like [`symbolicate`], but also returns the source location of each symbol.
+pub fn symbolicate_with_locations(profile: &StackProfile) -> BTreeMap<usize, Vec<Frame>> {
+    resolve(profile, Demangle::Rust)
+}
+
+/// Like [`symbolicate_with_locations`], but resolves addresses against the
+/// object file at `image` rather than against this process.
+///
+/// `load_bias` is the difference between the addresses in the profile and the
+/// corresponding addresses in the object file, i.e., the load address of the
+/// image in the profiled process (see [`images::MappedImage`]). Addresses are
+/// resolved using the file's debug info if it has any, or else its symbol
+/// table. Addresses that the file does not cover resolve to no frames.
+#[cfg(feature = "offline-symbols")]
+pub fn symbolicate_offline(
+    profile: &StackProfile,
+    image: &std::path::Path,
+    load_bias: usize,
+) -> anyhow::Result<BTreeMap<usize, Vec<Frame>>> {
+    use object::Object;
+
+    let data = std::fs::read(image)?;
+    let file = object::File::parse(&*data)?;
+    let context = addr2line::Context::new(&file)?;
+    let symbol_map = file.symbol_map();
+    let module = image
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+
+    let mut frames_by_addr = BTreeMap::new();
+    for addr in unique_addrs(profile) {
+        let probe = addr.wrapping_sub(load_bias) as u64;
+        let mut frames = vec![];
+        let mut iter = context.find_frames(probe)?;
+        while let Some(frame) = iter.next()? {
+            let name = match &frame.function {
+                Some(function) => function.demangle()?.into_owned(),
+                None => "???".to_string(),
+            };
+            let location = frame.location.as_ref();
+            frames.push(Frame {
+                name,
+                filename: location.and_then(|l| l.file).map(str::to_string),
+                lineno: location.and_then(|l| l.line),
+                module: module.clone(),
+            });
+        }
+        if frames.is_empty() {
+            if let Some(symbol) = symbol_map.get(probe) {
+                let name = addr2line::demangle_auto(symbol.name().into(), None);
+                frames.push(Frame {
+                    name: name.into_owned(),
+                    filename: None,
+                    lineno: None,
+                    module: module.clone(),
+                });
+            }
+        }
+        // `find_frames` returns the innermost frame first.
+        frames.reverse();
+        frames_by_addr.insert(addr, frames);
+    }
+    Ok(frames_by_addr)
+}
+
+/// Like [`symbolicate`], but periodically reports its progress by calling
+/// `on_progress` with the number of distinct addresses resolved so far and
+/// the total number of distinct addresses.
+///
+/// `on_progress` is called after every [`PROGRESS_INTERVAL`] addresses, and
+/// always once after the last address, even if there are none.
+pub fn symbolicate_with_progress<F: FnMut(usize, usize)>(
+    profile: &StackProfile,
+    mut on_progress: F,
+) -> HashMap<usize, Vec<String>> {
+    resolve_with_progress(profile, Demangle::Rust, &mut on_progress)
+        .into_iter()
+        .map(|(addr, frames)| {
+            let names = frames.into_iter().map(|frame| frame.name).collect();
+            (addr, names)
+        })
+        .collect()
+}
+
+/// The number of addresses between calls to the progress callback of
+/// [`symbolicate_with_progress`].
+pub const PROGRESS_INTERVAL: usize = 1000;
+
+fn resolve(profile: &StackProfile, demangle_mode: Demangle) -> BTreeMap<usize, Vec<Frame>> {
+    resolve_with_progress(profile, demangle_mode, &mut |_resolved, _total| ())
+}
+
+fn resolve_with_progress(
+    profile: &StackProfile,
+    demangle_mode: Demangle,
+    on_progress: &mut dyn FnMut(usize, usize),
+) -> BTreeMap<usize, Vec<Frame>> {
+    let addrs = unique_addrs(profile);
+    let total = addrs.len();
+    let mut frames_by_addr = BTreeMap::new();
+    for (i, addr) in addrs.into_iter().enumerate() {
+        let frames = match profile.symbols.get(&addr) {
+            Some(syms) => syms
+                .iter()
+                .map(|name| Frame {
+                    name: name.clone(),
+                    filename: None,
+                    lineno: None,
+                    module: None,
+                })
+                .collect(),
+            None => resolve_addr(addr, demangle_mode),
+        };
+        frames_by_addr.insert(addr, frames);
+        let resolved = i + 1;
+        if resolved % PROGRESS_INTERVAL == 0 && resolved != total {
+            on_progress(resolved, total);
+        }
+    }
+    on_progress(total, total);
+    frames_by_addr
+}
+
+/// Parses an address in hex, with a leading `0x`.
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parses a mapped image in the format of its `Display` implementation.
+fn parse_mapped_image(s: &str) -> Option<images::MappedImage> {
+    let mut parts = s.splitn(3, ' ');
+    let load_address = parse_hex(parts.next()?)?;
+    let build_id = match parts.next()? {
+        "-" => None,
+        build_id => Some(build_id.to_string()),
+    };
+    let path = parts.next()?.to_string();
+    Some(images::MappedImage {
+        path,
+        load_address,
+        build_id,
+    })
+}
+
+/// Returns the distinct addresses of the stacks of `profile`, in ascending
+/// order.
+fn unique_addrs(profile: &StackProfile) -> Vec<usize> {
     let mut all_addrs = vec![];
     for (stack, _annotation) in profile.stacks.iter() {
         all_addrs.extend(stack.addrs.iter().cloned());
@@ -159,21 +1627,97 @@ pub fn symbolicate(profile: &StackProfile) -> HashMap<usize, Vec<String>> {
     all_addrs.sort_unstable();
     all_addrs.dedup();
     all_addrs
-        .into_iter()
-        .map(|addr| {
-            let mut syms = vec![];
-            backtrace::resolve(addr as *mut c_void, |sym| {
-                let name = sym
-                    .name()
-                    .map(|sn| sn.to_string())
-                    .unwrap_or_else(|| "???".to_string());
-                syms.push(name);
-            });
-            syms.reverse();
-            (addr, syms)
-        })
-        .collect()
 }
+
+fn resolve_addr(addr: usize, demangle_mode: Demangle) -> Vec<Frame> {
+    let module = module_of(addr);
+    let mut frames = vec![];
+    backtrace::resolve(addr as *mut c_void, |sym| {
+        let name = sym
+            .name()
+            .map(|sn| demangle(&sn, demangle_mode))
+            .unwrap_or_else(|| "???".to_string());
+        frames.push(Frame {
+            name,
+            filename: sym.filename().map(|f| f.to_string_lossy().into_owned()),
+            lineno: sym.lineno(),
+            module: module.clone(),
+        });
+    });
+    frames.reverse();
+    frames
+}
+
+/// Returns the name of the file of the image in this process that contains
+/// `addr`, without its directory.
+#[cfg(unix)]
+fn module_of(addr: usize) -> Option<String> {
+    use std::ffi::CStr;
+    use std::path::Path;
+
+    let mut info = std::mem::MaybeUninit::<libc::Dl_info>::uninit();
+    // SAFETY: `dladdr` only writes to `info`, and only reads from `addr`'s
+    // value, not from the memory it points to.
+    let info = unsafe {
+        if libc::dladdr(addr as *const c_void, info.as_mut_ptr()) == 0 {
+            return None;
+        }
+        info.assume_init()
+    };
+    if info.dli_fname.is_null() {
+        return None;
+    }
+    // SAFETY: `dladdr` returned a valid C string, which lives as long as the
+    // image is loaded.
+    let path = unsafe { CStr::from_ptr(info.dli_fname) };
+    let path = Path::new(path.to_str().ok()?);
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn module_of(_addr: usize) -> Option<String> {
+    None
+}
+
+lazy_static! {
+    static ref SYMBOL_CACHE: Mutex<BTreeMap<usize, Vec<String>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Like [`symbolicate`], but remembers the symbols of each address for the
+/// lifetime of the process, so that only addresses that have not been seen
+/// before are resolved.
+///
+/// The cache is keyed by absolute address, so its entries are only valid
+/// within this process. It grows with every distinct address symbolicated;
+/// use [`clear_symbol_cache`] to reclaim its memory.
+pub fn symbolicate_cached(profile: &StackProfile) -> HashMap<usize, Vec<String>> {
+    let mut cache = SYMBOL_CACHE.lock().expect("lock poisoned");
+    let addrs = unique_addrs(profile);
+    let mut symbols = HashMap::new();
+    for addr in addrs {
+        let syms = match profile.symbols.get(&addr) {
+            Some(syms) => syms.clone(),
+            None => cache
+                .entry(addr)
+                .or_insert_with(|| {
+                    resolve_addr(addr, Demangle::Rust)
+                        .into_iter()
+                        .map(|frame| frame.name)
+                        .collect()
+                })
+                .clone(),
+        };
+        symbols.insert(addr, syms);
+    }
+    symbols
+}
+
+/// Empties the cache used by [`symbolicate_cached`].
+pub fn clear_symbol_cache() {
+    SYMBOL_CACHE.lock().expect("lock poisoned").clear();
+}
+
 /// Given some stack traces along with their weights,
 /// collate them into a tree structure by function name.
 ///
@@ -189,11 +1733,24 @@ pub fn symbolicate(profile: &StackProfile) -> HashMap<usize, Vec<String>> {
 ///  v
 /// "h" (50)
 pub fn collate_stacks(profile: StackProfile) -> WeightedSymbolTrie {
+    collate_stacks_inner(profile, None)
+}
+
+fn collate_stacks_inner(profile: StackProfile, max_depth: Option<usize>) -> WeightedSymbolTrie {
     let addr_to_symbols = symbolicate(&profile);
+    collate_symbolicated_stacks(profile, &addr_to_symbols, max_depth)
+}
+
+fn collate_symbolicated_stacks(
+    profile: StackProfile,
+    addr_to_symbols: &HashMap<usize, Vec<String>>,
+    max_depth: Option<usize>,
+) -> WeightedSymbolTrie {
     let mut trie = WeightedSymbolTrie::new();
     let StackProfile {
         annotations,
         stacks,
+        ..
     } = profile;
     let any_annotation = !annotations.is_empty();
     for (stack, annotation) in stacks {
@@ -206,15 +1763,943 @@ pub fn collate_stacks(profile: StackProfile) -> WeightedSymbolTrie {
         } else {
             0
         };
-        for name in stack
+        for (depth, name) in stack
             .addrs
             .into_iter()
             .flat_map(|addr| addr_to_symbols.get(&addr).unwrap().iter())
+            .enumerate()
         {
             trie.node_mut(cur).weight += stack.weight;
+            if max_depth == Some(depth) {
+                cur = trie.step(cur, "[deeper]");
+                break;
+            }
             cur = trie.step(cur, name);
         }
         trie.node_mut(cur).weight += stack.weight;
     }
     trie
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::images::{MappedImage, MappedImages};
+    use super::{
+        clear_symbol_cache, collate_symbolicated_stacks, demangle, symbolicate, symbolicate_cached,
+        symbolicate_opts, symbolicate_with_locations, symbolicate_with_progress, Demangle,
+        MzfgError, ProfStartTime, StackMeta, StackProfile, WeightedStack, PROGRESS_INTERVAL,
+    };
+
+    #[test]
+    fn test_prof_start_time() {
+        assert_eq!(ProfStartTime::TimeImmemorial.elapsed(), None);
+        assert_eq!(
+            ProfStartTime::TimeImmemorial.to_string(),
+            "since process start"
+        );
+        // The monotonic clock may have started less than 5s ago, e.g. on a
+        // freshly booted machine, in which case there is nothing to test.
+        let start = match Instant::now().checked_sub(Duration::from_secs(5)) {
+            Some(start) => ProfStartTime::Instant(start),
+            None => return,
+        };
+        assert!(start.elapsed().unwrap() >= Duration::from_secs(5));
+        assert!(start.to_string().starts_with("for 5"));
+    }
+
+    #[test]
+    fn test_weight_is_integral() {
+        assert!(WeightedStack::from_count(vec![0x1], 42).weight_is_integral());
+        assert!(WeightedStack::from_count(vec![0x1], 1 << 53).weight_is_integral());
+        assert!(!WeightedStack::from_count(vec![0x1], (1 << 53) + 2).weight_is_integral());
+        for weight in [0.5, -1.5, f64::NAN, f64::INFINITY] {
+            let stack = WeightedStack {
+                addrs: vec![0x1],
+                weight,
+            };
+            assert!(!stack.weight_is_integral());
+        }
+    }
+
+    #[test]
+    fn test_leaf_shares() {
+        // These addresses are not mapped, so they are named by their hex value.
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1],
+                weight: 1.0,
+            },
+            None,
+        );
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x2, 0x3],
+                weight: 3.0,
+            },
+            Some("anno"),
+        );
+        profile.push(
+            WeightedStack {
+                addrs: vec![],
+                weight: 4.0,
+            },
+            None,
+        );
+        let shares = profile.leaf_shares();
+        assert_eq!(shares.len(), 2);
+        assert_eq!(shares["0x1"], 0.125);
+        assert_eq!(shares["0x3"], 0.375);
+    }
+
+    #[test]
+    fn test_depth_quantile() {
+        let mut profile = StackProfile::default();
+        assert_eq!(profile.depth_quantile(0.5), 0);
+        for (depth, weight) in [(3, 1.0), (1, 2.0), (5, 1.0), (2, 0.0)] {
+            let addrs = vec![0x1; depth];
+            profile.push(WeightedStack { addrs, weight }, None);
+        }
+        assert_eq!(profile.depth_quantile(0.0), 1);
+        assert_eq!(profile.depth_quantile(0.5), 1);
+        assert_eq!(profile.depth_quantile(0.6), 3);
+        assert_eq!(profile.depth_quantile(0.75), 3);
+        assert_eq!(profile.depth_quantile(1.0), 5);
+    }
+
+    #[test]
+    fn test_to_collapsed() {
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x2],
+                weight: 30.0,
+            },
+            None,
+        );
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x3],
+                weight: 40.5,
+            },
+            Some("compaction"),
+        );
+        assert_eq!(
+            profile.to_collapsed(false),
+            "0x1;0x2 30\n0x1;0x3;[anno=compaction] 40.5\n"
+        );
+    }
+
+    #[test]
+    fn test_to_pprof() {
+        use std::io::Read;
+
+        use flate2::read::GzDecoder;
+        use pprof::protos::{Message, Profile};
+
+        let decode = |bytes: Vec<u8>| {
+            let mut buf = vec![];
+            GzDecoder::new(&*bytes).read_to_end(&mut buf).unwrap();
+            Profile::decode(&*buf).unwrap()
+        };
+
+        let profile = decode(StackProfile::default().to_pprof(("space", "bytes"), None));
+        assert!(profile.sample.is_empty());
+        assert!(profile.comment.is_empty());
+        assert_eq!(profile.string_table[0], "");
+
+        let mut profile = StackProfile::default();
+        profile.push(WeightedStack::from_count(vec![0x1], 1 << 53), None);
+        let profile = decode(profile.to_pprof(("samples", "count"), None));
+        assert_eq!(profile.sample[0].value, vec![1 << 53]);
+        assert!(profile.comment.is_empty());
+
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x2],
+                weight: 30.4,
+            },
+            Some("compaction"),
+        );
+        let profile = decode(profile.to_pprof(("space", "bytes"), Some(512 * 1024)));
+        let string = |id: i64| profile.string_table[id as usize].as_str();
+        assert_eq!(string(profile.sample_type[0].r#type), "space");
+        assert_eq!(string(profile.sample_type[0].unit), "bytes");
+        assert_eq!(profile.period, 512 * 1024);
+        assert_eq!(profile.sample.len(), 1);
+        let sample = &profile.sample[0];
+        assert_eq!(sample.value, vec![30]);
+        assert_eq!(
+            string(profile.comment[0]),
+            "sample values were rounded to the nearest integer"
+        );
+        assert_eq!(string(sample.label[0].str), "compaction");
+        let addresses: Vec<_> = sample
+            .location_id
+            .iter()
+            .map(|id| profile.location[*id as usize - 1].address)
+            .collect();
+        assert_eq!(addresses, vec![0x2, 0x1]);
+    }
+
+    #[test]
+    fn test_from_collapsed() {
+        let input = "main;foo;bar 30\nmain;foo;quux;[anno=compaction] 40.5  \n\nmain;bar 1\n";
+        let profile = StackProfile::from_collapsed(input).unwrap();
+        let stacks: Vec<_> = profile
+            .iter()
+            .map(|(stack, anno)| (stack.addrs.clone(), stack.weight, anno))
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                (vec![0, 1, 2], 30.0, None),
+                (vec![0, 1, 3], 40.5, Some("compaction")),
+                (vec![0, 2], 1.0, None),
+            ]
+        );
+        assert_eq!(
+            profile.to_collapsed(true),
+            "main;foo;bar 30\nmain;foo;quux;[anno=compaction] 40.5\nmain;bar 1\n"
+        );
+
+        let err = StackProfile::from_collapsed("main;foo 30\nmain;foo x\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid weight: x");
+        let err = StackProfile::from_collapsed("main;;foo 30\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: empty frame");
+        let err = StackProfile::from_collapsed("main\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: missing weight");
+    }
+
+    #[test]
+    fn test_merge() {
+        let stack = |addr| WeightedStack {
+            addrs: vec![addr],
+            weight: 1.0,
+        };
+        let mut a = StackProfile::default();
+        a.push(stack(0x1), Some("compaction"));
+        a.push(stack(0x2), None);
+        let mut b = StackProfile::default();
+        b.push(stack(0x3), Some("dataflow"));
+        b.push(stack(0x4), Some("compaction"));
+        let c = StackProfile::from_collapsed("main;foo 1\n").unwrap();
+        let d = StackProfile::from_collapsed("main;bar 1\n").unwrap();
+
+        let merged: StackProfile = vec![a, b, c, d].into_iter().collect();
+        assert_eq!(merged.annotations, vec!["compaction", "dataflow"]);
+        let annotations: Vec<_> = merged.iter().map(|(_stack, anno)| anno).collect();
+        assert_eq!(
+            annotations,
+            vec![
+                Some("compaction"),
+                None,
+                Some("dataflow"),
+                Some("compaction"),
+                None,
+                None
+            ]
+        );
+        assert_eq!(
+            merged.to_collapsed(false),
+            "0x1;[anno=compaction] 1\n0x2 1\n0x3;[anno=dataflow] 1\n\
+             0x4;[anno=compaction] 1\n0x0;0x1 1\n0x0;0x2 1\n"
+        );
+    }
+
+    #[test]
+    fn test_weight_by_annotation() {
+        let mut profile = StackProfile::default();
+        assert_eq!(profile.total_weight(), 0.0);
+        assert!(profile.weight_by_annotation().is_empty());
+        for (weight, annotation) in [
+            (1.0, None),
+            (2.0, Some("compaction")),
+            (4.0, None),
+            (8.0, Some("compaction")),
+            (16.0, Some("dataflow")),
+        ] {
+            let stack = WeightedStack {
+                addrs: vec![0x1],
+                weight,
+            };
+            profile.push(stack, annotation);
+        }
+        assert_eq!(profile.total_weight(), 31.0);
+        let weights: Vec<_> = profile.weight_by_annotation().into_iter().collect();
+        assert_eq!(
+            weights,
+            vec![
+                (None, 5.0),
+                (Some("compaction"), 10.0),
+                (Some("dataflow"), 16.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut profile = StackProfile::default();
+        for (addr, annotation) in [
+            (0x1, Some("compaction")),
+            (0x2, Some("dataflow")),
+            (0x3, None),
+            (0x4, Some("dataflow")),
+        ] {
+            let stack = WeightedStack {
+                addrs: vec![addr],
+                weight: 1.0,
+            };
+            profile.push(stack, annotation);
+        }
+        let filtered = profile.filter(|annotation| annotation == Some("dataflow"));
+        assert_eq!(filtered.annotations, vec!["dataflow"]);
+        let stacks: Vec<_> = filtered
+            .iter()
+            .map(|(stack, annotation)| (stack.addrs.clone(), annotation))
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![(vec![0x2], Some("dataflow")), (vec![0x4], Some("dataflow"))]
+        );
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut profile = StackProfile::default();
+        for (addrs, annotation) in [
+            (vec![0x1], Some("runtime")),
+            (vec![0x1, 0x2, 0x3], Some("dataflow")),
+            (vec![0x1, 0x2], None),
+            (vec![0x1, 0x2, 0x3, 0x4], Some("compaction")),
+        ] {
+            profile.push(WeightedStack { addrs, weight: 1.0 }, annotation);
+        }
+        profile.retain(|stack, _annotation| stack.addrs.len() >= 3);
+        assert_eq!(profile.annotations, vec!["dataflow", "compaction"]);
+        for (annotation, idx) in &profile.annotation_idxs {
+            assert_eq!(&profile.annotations[*idx], annotation);
+        }
+        let stacks: Vec<_> = profile
+            .iter()
+            .map(|(stack, annotation)| (stack.addrs.len(), annotation))
+            .collect();
+        assert_eq!(stacks, vec![(3, Some("dataflow")), (4, Some("compaction"))]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let before =
+            StackProfile::from_collapsed("main;foo 10\nmain;bar 5\nmain;foo 2\nmain;baz 1\n")
+                .unwrap();
+        let after =
+            StackProfile::from_collapsed("main;bar 5\nmain;quux 3\nmain;foo;[anno=x] 4\n").unwrap();
+        let diff = StackProfile::diff(&before, &after);
+        assert_eq!(
+            diff.to_collapsed(true),
+            "main;foo -8\nmain;baz -1\nmain;quux 3\n"
+        );
+    }
+
+    #[test]
+    fn test_to_mzfg() {
+        let mut profile = StackProfile::from_collapsed("main;foo 10\nmain;bar 2.5\n").unwrap();
+        profile
+            .symbols
+            .insert(0x2, vec!["<T as a\\b;c>::f".to_string()]);
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x0],
+                weight: 1.0,
+            },
+            Some("compaction"),
+        );
+        let expected = "mz_fg_version: 1
+kind: heap
+
+0x0;0x1; 10
+0x0;0x2; 2.5
+0x0; 1 compaction
+
+0x0 main;
+0x1 foo;
+0x2 <T as a\\\\b\\;c>::f;
+";
+        assert_eq!(
+            profile.to_mzfg(true, &[("kind", "heap")]).unwrap(),
+            expected
+        );
+        let images = MappedImages {
+            images: vec![MappedImage {
+                path: "/bin/materialized".to_string(),
+                load_address: 0x5000,
+                build_id: Some("abcd".to_string()),
+            }],
+        };
+        assert_eq!(
+            profile
+                .clone()
+                .with_images(images)
+                .to_mzfg(false, &[])
+                .unwrap(),
+            "mz_fg_version: 1
+mapped_image: 0x5000 abcd /bin/materialized
+
+0x0;0x1; 10
+0x0;0x2; 2.5
+0x0; 1 compaction
+"
+        );
+        let err = profile.to_mzfg(false, &[("bad:key", "value")]).unwrap_err();
+        assert_eq!(err.to_string(), "invalid mzfg header: \"bad:key\"");
+        let err = profile
+            .to_mzfg(false, &[("key", "bad\nvalue")])
+            .unwrap_err();
+        assert!(matches!(err, MzfgError::InvalidHeader { key } if key == "key"));
+        assert_eq!(
+            profile.to_mzfg(false, &[]).unwrap(),
+            "mz_fg_version: 1
+
+0x0;0x1; 10
+0x0;0x2; 2.5
+0x0; 1 compaction
+"
+        );
+    }
+
+    #[test]
+    fn test_to_mzfg_mark_unresolved() {
+        let mut profile = StackProfile::from_collapsed("main;foo 10\n").unwrap();
+        profile.symbols.insert(0x2, vec![]);
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x0, 0x2],
+                weight: 1.0,
+            },
+            None,
+        );
+        let unmarked = "mz_fg_version: 1
+
+0x0;0x1; 10
+0x0;0x2; 1
+
+0x0 main;
+0x1 foo;
+";
+        assert_eq!(profile.to_mzfg(true, &[]).unwrap(), unmarked);
+        assert_eq!(
+            profile.to_mzfg_opts(true, false, &[]).unwrap(),
+            profile.to_mzfg(true, &[]).unwrap()
+        );
+        let marked = profile.to_mzfg_opts(true, true, &[]).unwrap();
+        assert_eq!(marked, format!("{}0x2 ???;\n", unmarked));
+        assert_eq!(
+            StackProfile::from_mzfg(&marked).unwrap().symbols[&0x2],
+            vec!["???".to_string()]
+        );
+        assert_eq!(
+            profile.to_mzfg_opts(false, true, &[]).unwrap(),
+            profile.to_mzfg(false, &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_mzfg_gzip() {
+        use std::io::Read;
+
+        let profile = StackProfile::from_collapsed("main;foo 10\nmain;bar 2.5\n").unwrap();
+        let gzipped = profile.to_mzfg_gzip(true, &[("kind", "heap")]).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&*gzipped)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(
+            decompressed,
+            profile.to_mzfg(true, &[("kind", "heap")]).unwrap()
+        );
+        assert!(profile.to_mzfg_gzip(true, &[("bad:key", "")]).is_err());
+    }
+
+    #[test]
+    fn test_from_mzfg() {
+        let mut profile = StackProfile::from_collapsed("main;foo 10\nmain;bar 2.5\n").unwrap();
+        profile
+            .symbols
+            .insert(0x2, vec!["<T as a\\b;c>::f".to_string(), "g".to_string()]);
+        profile.push(
+            WeightedStack {
+                addrs: vec![],
+                weight: 1.0,
+            },
+            Some("compaction space"),
+        );
+        let profile = profile.with_images(MappedImages {
+            images: vec![MappedImage {
+                path: "/bin/my materialized".to_string(),
+                load_address: 0x5000,
+                build_id: None,
+            }],
+        });
+        let mzfg = profile.to_mzfg(true, &[("kind", "heap")]).unwrap();
+        let parsed = StackProfile::from_mzfg(&mzfg).unwrap();
+        let stacks = |profile: &StackProfile| -> Vec<_> {
+            profile
+                .iter()
+                .map(|(stack, annotation)| {
+                    (
+                        stack.addrs.clone(),
+                        stack.weight,
+                        annotation.map(str::to_string),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(stacks(&parsed), stacks(&profile));
+        assert_eq!(parsed.symbols, profile.symbols);
+        assert_eq!(parsed.images, profile.images);
+        assert_eq!(parsed.to_mzfg(true, &[("kind", "heap")]).unwrap(), mzfg);
+
+        let err = StackProfile::from_mzfg("mz_fg_version: 1\n\n0x1; ten\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: invalid weight: ten");
+        let err = StackProfile::from_mzfg("mz_fg_version: 2\n\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unsupported version: 2");
+    }
+
+    #[test]
+    fn test_push_many_annotations() {
+        let mut profile = StackProfile::default();
+        for i in 0..100_000 {
+            let stack = WeightedStack {
+                addrs: vec![0x1],
+                weight: 1.0,
+            };
+            profile.push(stack, Some(&format!("anno{}", i % 1000)));
+        }
+        assert_eq!(profile.stack_count(), 100_000);
+        assert_eq!(profile.annotation_count(), 1000);
+        assert!(!profile.is_empty());
+        assert!(StackProfile::default().is_empty());
+        for (i, (_stack, annotation)) in profile.iter().enumerate() {
+            assert_eq!(annotation, Some(&*format!("anno{}", i % 1000)));
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut profile = StackProfile::from_collapsed("main;foo 10\n").unwrap();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x0, 0x1],
+                weight: 2.5,
+            },
+            Some("compaction"),
+        );
+        let json = serde_json::to_string(&profile).unwrap();
+        assert_eq!(
+            json,
+            r#"{"stacks":[[[0,1],10.0,null],[[0,1],2.5,"compaction"]],"symbols":{"0":["main"],"1":["foo"]}}"#
+        );
+        let roundtripped: StackProfile = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            roundtripped.to_mzfg(true, &[]).unwrap(),
+            profile.to_mzfg(true, &[]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_symbolicate_with_locations() {
+        let mut addrs = vec![];
+        backtrace::trace(|frame| {
+            addrs.push(frame.ip() as usize);
+            true
+        });
+        let mut profile = StackProfile::default();
+        profile.push(WeightedStack { addrs, weight: 1.0 }, None);
+        let frames = symbolicate_with_locations(&profile);
+        let frame = frames
+            .values()
+            .flatten()
+            .find(|frame| frame.name.contains("test_symbolicate_with_locations"))
+            .unwrap();
+        assert!(frame.filename.as_ref().unwrap().ends_with("lib.rs"));
+        assert!(frame.lineno.is_some());
+        #[cfg(unix)]
+        {
+            let exe = std::env::current_exe().unwrap();
+            let exe = exe.file_name().unwrap().to_string_lossy();
+            assert_eq!(frame.module.as_deref(), Some(&*exe));
+        }
+    }
+
+    #[test]
+    fn test_symbolicate_drop_unknown() {
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x2, 0x3],
+                weight: 1.0,
+            },
+            None,
+        );
+        profile.symbols.insert(0x2, vec!["???".to_string()]);
+        profile.symbols.insert(0x3, vec!["main".to_string()]);
+        let symbols = symbolicate_opts(&profile, Demangle::Rust, false);
+        assert!(symbols.contains_key(&0x1));
+        assert!(symbols.contains_key(&0x2));
+        let symbols = symbolicate_opts(&profile, Demangle::Rust, true);
+        assert!(!symbols.contains_key(&0x1));
+        assert!(!symbols.contains_key(&0x2));
+        assert_eq!(symbols[&0x3], vec!["main"]);
+        assert_eq!(profile.iter().next().unwrap().0.addrs, vec![0x1, 0x2, 0x3]);
+    }
+
+    #[test]
+    fn test_demangle() {
+        let mangled = "_ZN4core3fmt5write17h0123456789abcdefE";
+        let name = backtrace::SymbolName::new(mangled.as_bytes());
+        assert_eq!(demangle(&name, Demangle::None), mangled);
+        assert_eq!(
+            demangle(&name, Demangle::Rust),
+            "core::fmt::write::h0123456789abcdef"
+        );
+        assert_eq!(demangle(&name, Demangle::Full), "core::fmt::write");
+    }
+
+    #[test]
+    fn test_symbolicate_cached() {
+        let mut addrs = vec![];
+        backtrace::trace(|frame| {
+            addrs.push(frame.ip() as usize);
+            true
+        });
+        let mut profile = StackProfile::default();
+        profile.push(WeightedStack { addrs, weight: 1.0 }, None);
+        let expected = symbolicate(&profile);
+        assert_eq!(symbolicate_cached(&profile), expected);
+        // The second call is served from the cache.
+        assert_eq!(symbolicate_cached(&profile), expected);
+        clear_symbol_cache();
+        assert_eq!(symbolicate_cached(&profile), expected);
+    }
+
+    #[test]
+    fn test_symbolicate_with_progress() {
+        let mut calls = vec![];
+        symbolicate_with_progress(&StackProfile::default(), |resolved, total| {
+            calls.push((resolved, total))
+        });
+        assert_eq!(calls, vec![(0, 0)]);
+
+        let mut profile = StackProfile::from_collapsed("a;b 1\n").unwrap();
+        let addrs = (0x10..0x10 + PROGRESS_INTERVAL * 2).collect();
+        profile.push(WeightedStack { addrs, weight: 1.0 }, None);
+        let total = PROGRESS_INTERVAL * 2 + 2;
+        let mut calls = vec![];
+        symbolicate_with_progress(&profile, |resolved, total| calls.push((resolved, total)));
+        assert_eq!(
+            calls,
+            vec![
+                (PROGRESS_INTERVAL, total),
+                (PROGRESS_INTERVAL * 2, total),
+                (total, total)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_top_n() {
+        let profile =
+            StackProfile::from_collapsed("a 1\nb;[anno=x] 3\nc 2\nd;[anno=y] 3\ne 1\n").unwrap();
+        let top = profile.top_n(3);
+        assert_eq!(top.to_collapsed(true), "b;[anno=x] 3\nc 2\nd;[anno=y] 3\n");
+        assert_eq!(top.annotations, vec!["x", "y"]);
+        assert_eq!(profile.top_n(1).annotations, vec!["x"]);
+
+        let empty = profile.top_n(0);
+        assert_eq!(empty.iter().count(), 0);
+        assert!(empty.annotations.is_empty());
+
+        let all = profile.top_n(usize::MAX);
+        assert_eq!(all.to_collapsed(true), profile.to_collapsed(true));
+        assert_eq!(all.annotations, profile.annotations);
+    }
+
+    #[test]
+    fn test_scale() {
+        let mut profile = StackProfile::from_collapsed("a 1\nb;[anno=x] 2.5\n").unwrap();
+        assert_eq!(
+            profile.scaled(2.0).to_collapsed(true),
+            "a 2\nb;[anno=x] 5\n"
+        );
+        assert_eq!(
+            profile.scaled(0.0).to_collapsed(true),
+            "a 0\nb;[anno=x] 0\n"
+        );
+        profile.scale(4.0);
+        assert_eq!(profile.to_collapsed(true), "a 4\nb;[anno=x] 10\n");
+    }
+
+    #[test]
+    fn test_push_with_meta() {
+        let meta = |thread_id, thread_name: &str| StackMeta {
+            thread_id: Some(thread_id),
+            thread_name: Some(thread_name.to_string()),
+        };
+        let mut profile = StackProfile::default();
+        for (addrs, meta) in [
+            (vec![0x1, 0x2], Some(meta(7, "timely:work-0"))),
+            (vec![0x1, 0x2], Some(meta(8, "timely:work-1"))),
+            (vec![0x1, 0x2], None),
+            (vec![0x1, 0x2], Some(meta(7, "timely:work-0"))),
+        ] {
+            let stack = WeightedStack { addrs, weight: 1.0 };
+            match meta {
+                Some(meta) => profile.push_with_meta(stack, None, meta),
+                None => profile.push(stack, None),
+            }
+        }
+        assert_eq!(profile.metas.len(), 2);
+
+        // Metadata distinguishes otherwise identical stacks.
+        let aggregated = profile.aggregate();
+        let stacks: Vec<_> = aggregated
+            .iter_with_meta()
+            .map(|(stack, _annotation, meta)| (stack.weight, meta.and_then(|meta| meta.thread_id)))
+            .collect();
+        assert_eq!(stacks, vec![(1.0, None), (2.0, Some(7)), (1.0, Some(8))]);
+
+        // Metadata survives filtering and serialization.
+        let filtered = profile.filter(|_annotation| true);
+        let json = serde_json::to_string(&filtered).unwrap();
+        let roundtripped: StackProfile = serde_json::from_str(&json).unwrap();
+        let metas = |profile: &StackProfile| -> Vec<_> {
+            profile
+                .iter_with_meta()
+                .map(|(_stack, _annotation, meta)| meta.cloned())
+                .collect()
+        };
+        assert_eq!(metas(&roundtripped), metas(&profile));
+
+        let trace: Vec<serde_json::Value> =
+            serde_json::from_str(&profile.to_chrome_trace()).unwrap();
+        let tids: Vec<_> = trace.iter().map(|event| event["tid"].as_u64()).collect();
+        let expected = [7, 7, 8, 8, 0, 0, 7, 7].iter().map(|tid| Some(*tid));
+        assert!(tids.into_iter().eq(expected));
+    }
+
+    #[test]
+    fn test_to_chrome_trace() {
+        let mut profile = StackProfile::from_collapsed("main;foo 10\nmain;[anno=x] 2.5\n").unwrap();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x0, 0x1000],
+                weight: 1.0,
+            },
+            None,
+        );
+        let trace = profile.to_chrome_trace();
+        let events: Vec<serde_json::Value> = serde_json::from_str(&trace).unwrap();
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| {
+                assert_eq!(event["ph"], "X");
+                (
+                    event["name"].as_str().unwrap(),
+                    event["ts"].as_f64().unwrap(),
+                    event["dur"].as_f64().unwrap(),
+                    event["args"]["annotation"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("main", 0.0, 10.0, None),
+                ("foo", 0.0, 10.0, None),
+                ("main", 10.0, 2.5, Some("x")),
+                ("main", 12.5, 1.0, None),
+                ("0x1000", 12.5, 1.0, None),
+            ]
+        );
+        assert_eq!(StackProfile::default().to_chrome_trace(), "[]");
+    }
+
+    #[test]
+    fn test_to_speedscope() {
+        let mut profile =
+            StackProfile::from_collapsed("main;foo 10\nmain;bar;[anno=x] 2.5\n").unwrap();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x0, 0x1000],
+                weight: 1.0,
+            },
+            None,
+        );
+        let json: serde_json::Value = serde_json::from_str(&profile.to_speedscope("heap")).unwrap();
+        let frames: Vec<_> = json["shared"]["frames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| frame["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(frames, vec!["main", "foo", "bar", "[anno=x]", "0x1000"]);
+        let profile = &json["profiles"][0];
+        assert_eq!(profile["name"], "heap");
+        assert_eq!(profile["endValue"], 13.5);
+        assert_eq!(profile["weights"], serde_json::json!([10.0, 2.5, 1.0]));
+        let samples = profile["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 3);
+        for idx in samples.iter().flat_map(|sample| sample.as_array().unwrap()) {
+            assert!((idx.as_u64().unwrap() as usize) < frames.len());
+        }
+        assert_eq!(profile["samples"][1], serde_json::json!([0, 2, 3]));
+    }
+
+    #[test]
+    fn test_from_backtrace() {
+        let bt = backtrace::Backtrace::new_unresolved();
+        let profile = StackProfile::from_backtrace(&bt, 2.0, Some("panic"));
+        let stacks: Vec<_> = profile.iter().collect();
+        assert_eq!(stacks.len(), 1);
+        let (stack, annotation) = stacks[0];
+        assert_eq!(annotation, Some("panic"));
+        assert_eq!(stack.weight, 2.0);
+        let innermost = bt.frames()[0].ip() as usize;
+        assert_eq!(stack.addrs.last(), Some(&innermost));
+        assert_eq!(stack.addrs.len(), bt.frames().len());
+    }
+
+    #[test]
+    fn test_iter_reversed() {
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x2, 0x3],
+                weight: 1.0,
+            },
+            Some("x"),
+        );
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x4],
+                weight: 2.0,
+            },
+            None,
+        );
+        let before: Vec<_> = profile
+            .iter()
+            .map(|(stack, annotation)| (stack.addrs.clone(), stack.weight, annotation))
+            .collect();
+        let reversed: Vec<_> = profile
+            .iter_reversed()
+            .map(|(stack, annotation)| (stack.addrs, stack.weight, annotation))
+            .collect();
+        assert_eq!(
+            reversed,
+            vec![
+                (vec![0x3, 0x2, 0x1], 1.0, Some("x")),
+                (vec![0x4], 2.0, None)
+            ]
+        );
+        let after: Vec<_> = profile
+            .iter()
+            .map(|(stack, annotation)| (stack.addrs.clone(), stack.weight, annotation))
+            .collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let profile = StackProfile::from_collapsed(
+            "main;foo 1\nmain;bar 2\nmain;foo 1\nmain;foo;[anno=x] 4\nmain;foo 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profile.aggregate().to_collapsed(true),
+            "main;foo 3\nmain;foo;[anno=x] 4\nmain;bar 2\n"
+        );
+    }
+
+    #[cfg(all(feature = "offline-symbols", target_os = "linux"))]
+    #[test]
+    fn test_symbolicate_offline() {
+        let mut addrs = vec![];
+        backtrace::trace(|frame| {
+            // Point into the call instruction, like `backtrace::resolve` does.
+            if let Some(addr) = (frame.ip() as usize).checked_sub(1) {
+                addrs.push(addr);
+            }
+            true
+        });
+        let mut profile = StackProfile::default();
+        profile.push(WeightedStack { addrs, weight: 1.0 }, None);
+        let image = &MappedImages::capture().images[0];
+        let frames = super::symbolicate_offline(
+            &profile,
+            std::path::Path::new(&image.path),
+            image.load_address,
+        )
+        .unwrap();
+        let frame = frames
+            .values()
+            .flatten()
+            .find(|frame| frame.name.contains("test_symbolicate_offline"))
+            .unwrap();
+        assert!(frame.filename.as_ref().unwrap().ends_with("lib.rs"));
+    }
+
+    #[test]
+    fn test_summary() {
+        // These addresses are not mapped, so they are named by their hex value.
+        let mut profile = StackProfile::default();
+        for (addrs, weight) in [(vec![0x1], 1.0), (vec![0x2], 5.0), (vec![0x3, 0x1], 2.0)] {
+            profile.push(WeightedStack { addrs, weight }, None);
+        }
+        let summary = profile.summary(1);
+        assert_eq!(summary.total_weight, 8.0);
+        assert_eq!(summary.stack_count, 3);
+        assert_eq!(summary.top_leaves.len(), 1);
+        assert_eq!(summary.top_leaves[0].name, "0x2");
+        assert_eq!(summary.top_leaves[0].weight, 5.0);
+    }
+
+    #[test]
+    fn test_cap_depth_weighted() {
+        let addr_to_symbols = [
+            (0x1, vec!["f".to_string()]),
+            (0x2, vec!["g".to_string(), "h".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+        let mut profile = StackProfile::default();
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1, 0x2],
+                weight: 2.0,
+            },
+            None,
+        );
+        profile.push(
+            WeightedStack {
+                addrs: vec![0x1],
+                weight: 1.0,
+            },
+            None,
+        );
+        let trie = collate_symbolicated_stacks(profile, &addr_to_symbols, Some(2));
+        let mut nodes = vec![];
+        trie.dfs(
+            |node| nodes.push((node.name.clone(), node.weight)),
+            |_node, _is_last| {},
+        );
+        let expected = [("", 3.0), ("f", 3.0), ("g", 2.0), ("[deeper]", 2.0)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, weight)| (name.to_string(), *weight))
+            .collect();
+        assert_eq!(nodes, expected);
+    }
+}
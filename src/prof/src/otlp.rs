@@ -0,0 +1,349 @@
+// Copyright Materialize, Inc. and contributors. All rights reserved.
+//
+// Use of this software is governed by the Business Source License
+// included in the LICENSE file.
+//
+// As of the Change Date specified in that file, in accordance with
+// the Business Source License, use of this software will be governed
+// by the Apache License, Version 2.0.
+
+//! Export of profiles to the OpenTelemetry profiles signal.
+//!
+//! The signal is still in development, and its schema changes between
+//! releases of [opentelemetry-proto]. Profiles are encoded by hand as
+//! OTLP/JSON against the `v1development` schema of opentelemetry-proto
+//! v1.5.0, rather than with generated bindings, which do not support the
+//! pinned toolchain.
+//!
+//! [opentelemetry-proto]: https://github.com/open-telemetry/opentelemetry-proto
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{symbolicate_with_locations, StackProfile};
+
+/// The name of the instrumentation scope that produces the profiles.
+const SCOPE_NAME: &str = "prof";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfilesData {
+    resource_profiles: Vec<ResourceProfiles>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceProfiles {
+    resource: Resource,
+    scope_profiles: Vec<ScopeProfiles>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopeProfiles {
+    scope: InstrumentationScope,
+    profiles: Vec<Profile>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InstrumentationScope {
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    sample_type: Vec<ValueType>,
+    sample: Vec<Sample>,
+    location_table: Vec<Location>,
+    location_indices: Vec<i32>,
+    function_table: Vec<Function>,
+    attribute_table: Vec<KeyValue>,
+    string_table: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValueType {
+    type_strindex: i32,
+    unit_strindex: i32,
+}
+
+// 64-bit integers are encoded as decimal strings, as OTLP/JSON requires.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sample {
+    locations_start_index: i32,
+    locations_length: i32,
+    value: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attribute_indices: Vec<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    address: String,
+    line: Vec<Line>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Line {
+    function_index: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Function {
+    name_strindex: i32,
+    system_name_strindex: i32,
+    filename_strindex: i32,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AnyValue {
+    StringValue(String),
+    IntValue(String),
+}
+
+impl KeyValue {
+    fn string(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: AnyValue::StringValue(value.to_string()),
+        }
+    }
+}
+
+/// Assigns indices to distinct values in order of first appearance.
+struct Table<T> {
+    values: Vec<T>,
+    indices: HashMap<T, i32>,
+}
+
+impl<T: Clone + Eq + std::hash::Hash> Table<T> {
+    fn new() -> Table<T> {
+        Table {
+            values: vec![],
+            indices: HashMap::new(),
+        }
+    }
+
+    fn intern(&mut self, value: T) -> i32 {
+        if let Some(index) = self.indices.get(&value) {
+            return *index;
+        }
+        let index = self.values.len() as i32;
+        self.values.push(value.clone());
+        self.indices.insert(value, index);
+        index
+    }
+}
+
+impl StackProfile {
+    /// Encodes this profile as an OTLP/JSON `ProfilesData` message, which
+    /// describes a single profile of a resource with attributes
+    /// `resource_attrs`.
+    ///
+    /// As in [`StackProfile::to_pprof`], addresses are symbolicated into
+    /// locations whose lines refer to the functions of their symbols,
+    /// innermost first, and each stack becomes a sample whose locations are
+    /// ordered from the leaf to the root. The single value of a sample is the
+    /// stack's weight, rounded to the nearest integer, with sample type
+    /// `weight` and no unit, as a profile does not record what its weights
+    /// measure. Annotations are attached to samples as an `annotation`
+    /// attribute, and the thread ID and name of the stacks' metadata as
+    /// `thread.id` and `thread.name` attributes.
+    pub fn to_otlp(&self, resource_attrs: &[(&str, &str)]) -> Vec<u8> {
+        let addr_to_frames = symbolicate_with_locations(self);
+
+        // The first string in the string table must be the empty string.
+        let mut strings = Table::new();
+        strings.intern(String::new());
+        let mut functions = Table::new();
+        let mut attributes = Table::new();
+        let mut locations = vec![];
+        let mut location_ids = HashMap::new();
+        let mut location_indices = vec![];
+        let mut samples = vec![];
+
+        let sample_type = ValueType {
+            type_strindex: strings.intern("weight".to_string()),
+            unit_strindex: 0,
+        };
+        for (stack, annotation, meta) in self.iter_with_meta() {
+            let locations_start_index = location_indices.len() as i32;
+            for addr in stack.addrs.iter().rev() {
+                let id = *location_ids.entry(*addr).or_insert_with(|| {
+                    let frames = addr_to_frames.get(addr).map(Vec::as_slice).unwrap_or(&[]);
+                    let line = frames
+                        .iter()
+                        .rev()
+                        .map(|frame| {
+                            let name = strings.intern(frame.name.clone());
+                            let filename =
+                                strings.intern(frame.filename.clone().unwrap_or_default());
+                            Line {
+                                function_index: functions.intern((name, filename)),
+                                line: frame.lineno.map(|lineno| lineno.to_string()),
+                            }
+                        })
+                        .collect();
+                    locations.push(Location {
+                        address: addr.to_string(),
+                        line,
+                    });
+                    locations.len() as i32 - 1
+                });
+                location_indices.push(id);
+            }
+            let mut attribute_indices = vec![];
+            if let Some(annotation) = annotation {
+                attribute_indices
+                    .push(attributes.intern(KeyValue::string("annotation", annotation)));
+            }
+            if let Some(thread_id) = meta.and_then(|meta| meta.thread_id) {
+                attribute_indices.push(attributes.intern(KeyValue {
+                    key: "thread.id".to_string(),
+                    value: AnyValue::IntValue(thread_id.to_string()),
+                }));
+            }
+            if let Some(thread_name) = meta.and_then(|meta| meta.thread_name.as_deref()) {
+                attribute_indices
+                    .push(attributes.intern(KeyValue::string("thread.name", thread_name)));
+            }
+            samples.push(Sample {
+                locations_start_index,
+                locations_length: stack.addrs.len() as i32,
+                value: vec![(stack.weight.round() as i64).to_string()],
+                attribute_indices,
+            });
+        }
+
+        let function_table = functions
+            .values
+            .into_iter()
+            .map(|(name, filename)| Function {
+                name_strindex: name,
+                system_name_strindex: name,
+                filename_strindex: filename,
+            })
+            .collect();
+        let profile = Profile {
+            sample_type: vec![sample_type],
+            sample: samples,
+            location_table: locations,
+            location_indices,
+            function_table,
+            attribute_table: attributes.values,
+            string_table: strings.values,
+        };
+        let data = ProfilesData {
+            resource_profiles: vec![ResourceProfiles {
+                resource: Resource {
+                    attributes: resource_attrs
+                        .iter()
+                        .map(|(key, value)| KeyValue::string(key, value))
+                        .collect(),
+                },
+                scope_profiles: vec![ScopeProfiles {
+                    scope: InstrumentationScope { name: SCOPE_NAME },
+                    profiles: vec![profile],
+                }],
+            }],
+        };
+        serde_json::to_vec(&data).expect("serialization cannot fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{StackMeta, StackProfile, WeightedStack};
+
+    #[test]
+    fn test_to_otlp() {
+        let mut profile = StackProfile::from_collapsed("main;foo 3\nmain;bar 1\n").unwrap();
+        profile.push_with_meta(
+            WeightedStack {
+                addrs: vec![0x0, 0x1],
+                weight: 2.4,
+            },
+            Some("compaction"),
+            StackMeta {
+                thread_id: Some(7),
+                thread_name: Some("worker".to_string()),
+            },
+        );
+        let otlp = profile.to_otlp(&[("service.name", "materialized")]);
+        let otlp: Value = serde_json::from_slice(&otlp).unwrap();
+        let resource_profiles = &otlp["resourceProfiles"][0];
+        assert_eq!(
+            resource_profiles["resource"]["attributes"],
+            json!([{"key": "service.name", "value": {"stringValue": "materialized"}}])
+        );
+        let profile = &resource_profiles["scopeProfiles"][0]["profiles"][0];
+        let strings = profile["stringTable"].as_array().unwrap();
+        let string = |index: &Value| strings[index.as_u64().unwrap() as usize].as_str().unwrap();
+        assert_eq!(strings[0], "");
+        assert_eq!(string(&profile["sampleType"][0]["typeStrindex"]), "weight");
+
+        // Each sample's locations are ordered from the leaf to the root.
+        let leaf_and_root = |sample: &Value| {
+            let start = sample["locationsStartIndex"].as_u64().unwrap() as usize;
+            let len = sample["locationsLength"].as_u64().unwrap() as usize;
+            profile["locationIndices"].as_array().unwrap()[start..start + len]
+                .iter()
+                .map(|index| {
+                    let location = &profile["locationTable"][index.as_u64().unwrap() as usize];
+                    let function = &profile["functionTable"]
+                        [location["line"][0]["functionIndex"].as_u64().unwrap() as usize];
+                    string(&function["nameStrindex"])
+                })
+                .collect::<Vec<_>>()
+        };
+        let samples = profile["sample"].as_array().unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(leaf_and_root(&samples[0]), vec!["foo", "main"]);
+        assert_eq!(leaf_and_root(&samples[1]), vec!["bar", "main"]);
+        assert_eq!(samples[0]["value"], json!(["3"]));
+        assert!(samples[0].get("attributeIndices").is_none());
+
+        assert_eq!(leaf_and_root(&samples[2]), vec!["foo", "main"]);
+        assert_eq!(samples[2]["value"], json!(["2"]));
+        let attributes: Vec<_> = samples[2]["attributeIndices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|index| &profile["attributeTable"][index.as_u64().unwrap() as usize])
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![
+                &json!({"key": "annotation", "value": {"stringValue": "compaction"}}),
+                &json!({"key": "thread.id", "value": {"intValue": "7"}}),
+                &json!({"key": "thread.name", "value": {"stringValue": "worker"}}),
+            ]
+        );
+    }
+}
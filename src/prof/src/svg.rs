@@ -0,0 +1,119 @@
+// Copyright Materialize, Inc. and contributors. All rights reserved.
+//
+// Use of this software is governed by the Business Source License
+// included in the LICENSE file.
+//
+// As of the Change Date specified in that file, in accordance with
+// the Business Source License, use of this software will be governed
+// by the Apache License, Version 2.0.
+
+//! Rendering of profiles as self-contained SVG flamegraphs, with `inferno`.
+
+use inferno::flamegraph::{self, color::BasicPalette, Direction, Options};
+
+use crate::StackProfile;
+
+/// The colors of the frames of a flamegraph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    /// Reds, oranges, and yellows, as is traditional for CPU profiles.
+    Hot,
+    /// Greens, as is traditional for memory profiles.
+    Mem,
+    /// Blues, as is traditional for I/O profiles.
+    Io,
+}
+
+/// Options for [`StackProfile::to_flamegraph_svg`].
+#[derive(Clone, Debug)]
+pub struct FlamegraphOpts {
+    /// The title displayed above the flamegraph.
+    pub title: String,
+    /// The colors of the frames.
+    pub palette: Palette,
+    /// Whether to render an icicle graph, with the outermost frames at the
+    /// top, rather than a flamegraph, with them at the bottom.
+    pub inverted: bool,
+}
+
+impl Default for FlamegraphOpts {
+    fn default() -> Self {
+        FlamegraphOpts {
+            title: "Flame Graph".to_string(),
+            palette: Palette::Hot,
+            inverted: false,
+        }
+    }
+}
+
+impl StackProfile {
+    /// Renders this profile as a flamegraph in a self-contained SVG image.
+    ///
+    /// The profile is rendered from its symbolicated collapsed form, as
+    /// produced by [`StackProfile::to_collapsed`], so the weights of stacks
+    /// are truncated to integers. The color of each frame is derived from its
+    /// name, and so is stable across renderings.
+    pub fn to_flamegraph_svg(&self, opts: FlamegraphOpts) -> Result<String, anyhow::Error> {
+        let mut options = Options::default();
+        options.title = opts.title;
+        options.colors = flamegraph::Palette::Basic(match opts.palette {
+            Palette::Hot => BasicPalette::Hot,
+            Palette::Mem => BasicPalette::Mem,
+            Palette::Io => BasicPalette::Io,
+        });
+        options.deterministic = true;
+        if opts.inverted {
+            options.direction = Direction::Inverted;
+        }
+        let collapsed = self.to_collapsed(true);
+        let mut svg = vec![];
+        flamegraph::from_lines(&mut options, collapsed.lines(), &mut svg)?;
+        Ok(String::from_utf8(svg)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlamegraphOpts, Palette};
+    use crate::StackProfile;
+
+    #[test]
+    fn test_to_flamegraph_svg() {
+        let profile = StackProfile::from_collapsed("main;foo 3\nmain;bar 1\n").unwrap();
+        let opts = FlamegraphOpts {
+            title: "Heap".to_string(),
+            palette: Palette::Mem,
+            inverted: false,
+        };
+        let svg = profile.to_flamegraph_svg(opts.clone()).unwrap();
+        assert!(svg.contains("<svg "));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains(">Heap</text>"));
+        assert!(svg.contains("<title>foo (3 samples, 75.00%)</title>"));
+        assert!(svg.contains("<title>bar (1 samples, 25.00%)</title>"));
+        // Colors are derived from frame names, rather than chosen randomly.
+        assert_eq!(svg, profile.to_flamegraph_svg(opts.clone()).unwrap());
+        let inverted = profile
+            .to_flamegraph_svg(FlamegraphOpts {
+                inverted: true,
+                ..opts
+            })
+            .unwrap();
+        assert_ne!(svg, inverted);
+    }
+
+    #[test]
+    fn test_to_flamegraph_svg_escaping() {
+        let profile = StackProfile::from_collapsed("a<b&c\"d 1\n").unwrap();
+        let svg = profile
+            .to_flamegraph_svg(FlamegraphOpts {
+                title: "Heap & more".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(svg.contains(">Heap &amp; more</text>"));
+        assert!(svg.contains("a&lt;b&amp;c"));
+        assert!(!svg.contains("a<b"));
+        assert!(!svg.contains("b&c"));
+    }
+}
@@ -13,7 +13,7 @@ use anyhow::bail;
 use pprof::ProfilerGuard;
 use tokio::time::{self, Duration};
 
-use crate::{StackProfile, WeightedStack};
+use crate::{StackMeta, StackProfile, WeightedStack};
 
 /// # Safety
 ///
@@ -24,28 +24,182 @@ pub async unsafe fn prof_time(
     sample_freq: u32,
     merge_threads: bool,
 ) -> anyhow::Result<StackProfile> {
-    if sample_freq > (1e6 as u32) {
-        bail!("Sub-microsecond intervals are not supported.");
+    if sample_freq == 0 {
+        bail!("The sampling frequency must be positive.");
     }
-    let pg = ProfilerGuard::new(sample_freq as c_int)?;
+    let profiler = CpuProfiler::start_with_options(CpuProfilerOptions {
+        interval: Duration::from_secs(1) / sample_freq,
+        merge_threads,
+        ..Default::default()
+    })?;
     time::sleep(total_time).await;
-    let builder = pg.report();
-    let report = builder.build_unresolved()?;
-    let mut profile = <StackProfile as Default>::default();
-    for (f, weight) in report.data {
-        let thread_name;
-        let mut addrs: Vec<_> = f.frames.iter().map(|f| f.ip() as usize).collect();
-        addrs.reverse();
-        let weight = weight as f64;
-        let anno = if merge_threads {
-            None
-        } else {
-            thread_name = String::from_utf8_lossy(&f.thread_name[0..f.thread_name_length]);
-            Some(thread_name.as_ref())
-        };
-        let stack = WeightedStack { addrs, weight };
-        profile.push(stack, anno);
+    profiler.stop()
+}
+
+/// Options for a [`CpuProfiler`].
+#[derive(Clone, Debug)]
+pub struct CpuProfilerOptions {
+    /// The interval between samples, which must be at least a microsecond.
+    pub interval: Duration,
+    /// Whether to keep the samples of every thread of the process, rather
+    /// than only those of the OS thread that started the profiler.
+    ///
+    /// Keeping only the starting thread is meaningful only for synchronous
+    /// callers that start and stop the profiler on the same OS thread and do
+    /// the profiled work on it in between. A task on a multi-threaded async
+    /// runtime may move between threads, so its starting thread is arbitrary.
+    pub all_threads: bool,
+    /// Whether to merge the stacks of different threads, rather than
+    /// annotating each stack with the name of its thread.
+    pub merge_threads: bool,
+}
+
+impl Default for CpuProfilerOptions {
+    fn default() -> Self {
+        CpuProfilerOptions {
+            // An odd frequency, to avoid sampling in lockstep with periodic
+            // work.
+            interval: Duration::from_secs(1) / 99,
+            all_threads: true,
+            merge_threads: false,
+        }
+    }
+}
+
+/// A CPU profiler that periodically samples the stacks of running threads,
+/// using a `SIGPROF` timer, until it is stopped.
+pub struct CpuProfiler {
+    guard: ProfilerGuard<'static>,
+    options: CpuProfilerOptions,
+    thread_id: u64,
+}
+
+impl CpuProfiler {
+    /// Starts sampling every `interval`, with the default options otherwise.
+    ///
+    /// # Safety
+    ///
+    /// See [`CpuProfiler::start_with_options`].
+    pub unsafe fn start(interval: Duration) -> anyhow::Result<CpuProfiler> {
+        CpuProfiler::start_with_options(CpuProfilerOptions {
+            interval,
+            ..Default::default()
+        })
+    }
+
+    /// Starts sampling as configured by `options`.
+    ///
+    /// Only one profiler can run at a time.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else must be attempting to unwind backtraces until the profiler
+    /// is stopped. In particular, jemalloc memory profiling must be off.
+    pub unsafe fn start_with_options(options: CpuProfilerOptions) -> anyhow::Result<CpuProfiler> {
+        if options.interval < Duration::from_micros(1) {
+            bail!("Sub-microsecond intervals are not supported.");
+        }
+        let frequency = (1.0 / options.interval.as_secs_f64()).round().max(1.0) as c_int;
+        let guard = ProfilerGuard::new(frequency)?;
+        Ok(CpuProfiler {
+            guard,
+            options,
+            thread_id: libc::pthread_self() as u64,
+        })
+    }
+
+    /// Stops sampling, and returns the sampled stacks, each weighted by its
+    /// number of samples.
+    pub fn stop(self) -> anyhow::Result<StackProfile> {
+        let report = self.guard.report().build_unresolved()?;
+        let mut profile = <StackProfile as Default>::default();
+        for (f, weight) in report.data {
+            if !self.options.all_threads && f.thread_id != self.thread_id {
+                continue;
+            }
+            let thread_name = String::from_utf8_lossy(&f.thread_name[0..f.thread_name_length]);
+            let meta = StackMeta {
+                thread_id: Some(f.thread_id),
+                thread_name: Some(thread_name.to_string()),
+            };
+            let mut addrs: Vec<_> = f.frames.iter().map(|f| f.ip() as usize).collect();
+            addrs.reverse();
+            let anno = if self.options.merge_threads {
+                None
+            } else {
+                Some(thread_name.as_ref())
+            };
+            let stack = WeightedStack::from_count(addrs, weight as u64);
+            profile.push_with_meta(stack, anno, meta);
+        }
+        Ok(profile)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{CpuProfiler, CpuProfilerOptions};
+    use crate::StackProfile;
 
-    Ok(profile)
+    /// Keeps the current thread busy for `duration`.
+    fn spin(duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {}
+    }
+
+    /// Profiles a thread named `spinner` that is busy for a while, as well as
+    /// the current thread, which is busy for the same time.
+    fn profile(options: CpuProfilerOptions) -> StackProfile {
+        let duration = Duration::from_millis(300);
+        // SAFETY: no other test unwinds backtraces or profiles memory.
+        let profiler = unsafe { CpuProfiler::start_with_options(options) }.unwrap();
+        let spinner = thread::Builder::new()
+            .name("spinner".to_string())
+            .spawn(move || spin(duration))
+            .unwrap();
+        spin(duration);
+        spinner.join().unwrap();
+        profiler.stop().unwrap()
+    }
+
+    // The profilers run in one test, as only one can run at a time.
+    #[test]
+    fn test_cpu_profiler() {
+        let options = CpuProfilerOptions {
+            interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        // Each stack is annotated with the name of its thread.
+        let stacks = profile(options.clone());
+        assert!(stacks.iter().any(|(_, anno)| anno == Some("spinner")));
+        for (_, anno, meta) in stacks.iter_with_meta() {
+            let thread_name = meta.and_then(|meta| meta.thread_name.as_deref());
+            assert_eq!(anno, thread_name);
+        }
+
+        // Stacks are not annotated, but still record their thread.
+        let stacks = profile(CpuProfilerOptions {
+            merge_threads: true,
+            ..options.clone()
+        });
+        assert!(stacks.iter().all(|(_, anno)| anno.is_none()));
+        assert!(stacks.iter_with_meta().any(|(_, _, meta)| meta
+            .and_then(|meta| meta.thread_name.as_deref())
+            == Some("spinner")));
+
+        // Only the stacks of the current thread are kept.
+        let thread_id = unsafe { libc::pthread_self() } as u64;
+        let stacks = profile(CpuProfilerOptions {
+            all_threads: false,
+            ..options
+        });
+        assert!(!stacks.is_empty());
+        for (_, _, meta) in stacks.iter_with_meta() {
+            assert_eq!(meta.and_then(|meta| meta.thread_id), Some(thread_id));
+        }
+    }
 }
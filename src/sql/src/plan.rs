@@ -60,7 +60,7 @@ pub(crate) mod typeconv;
 pub use self::expr::{HirRelationExpr, HirScalarExpr};
 pub use error::PlanError;
 pub use explain::Explanation;
-pub use optimize::OptimizerConfig;
+pub use optimize::{LoweringCache, OptimizerConfig, OptimizerConfigBuilder};
 // This is used by sqllogictest to turn SQL values into `Datum`s.
 pub use query::{
     plan_default_expr, resolve_names, resolve_names_data_type, resolve_names_stmt,
@@ -8,17 +8,91 @@
 // by the Apache License, Version 2.0.
 
 ///! This module defines the API and logic for running optimization pipelines.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde::Serialize;
+
 use crate::plan::expr::HirRelationExpr;
 use crate::query_model::Model;
 
 use super::StatementContext;
 
 /// Feature flags for the [`HirRelationExpr::optimize_and_lower()`] logic.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct OptimizerConfig {
+    /// Whether to optimize the query graph model before lowering.
     pub qgm_optimizations: bool,
 }
 
+impl OptimizerConfig {
+    /// Returns a builder for an [`OptimizerConfig`] that differs from the
+    /// default only in the flags that are explicitly set.
+    pub fn builder() -> OptimizerConfigBuilder {
+        OptimizerConfigBuilder::default()
+    }
+
+    /// Renders the flags as a human-readable block with one `name: value`
+    /// line per flag.
+    ///
+    /// The flags appear in declaration order, so the output is stable and can
+    /// be attached verbatim to bug reports.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "qgm_optimizations: {}", self.qgm_optimizations).unwrap();
+        out
+    }
+
+    /// Reports the flags whose values differ between `self` and `other`.
+    ///
+    /// Each entry holds the name of a flag, its value in `self`, and its value
+    /// in `other`. The entries appear in declaration order and the result is
+    /// empty iff the two configs are equal.
+    pub fn diff(&self, other: &Self) -> Vec<(&'static str, String, String)> {
+        let mut diffs = vec![];
+        if self.qgm_optimizations != other.qgm_optimizations {
+            diffs.push((
+                "qgm_optimizations",
+                self.qgm_optimizations.to_string(),
+                other.qgm_optimizations.to_string(),
+            ));
+        }
+        diffs
+    }
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        OptimizerConfig {
+            qgm_optimizations: false,
+        }
+    }
+}
+
+/// A builder for an [`OptimizerConfig`], created by
+/// [`OptimizerConfig::builder()`].
+#[derive(Debug, Clone, Default)]
+pub struct OptimizerConfigBuilder {
+    qgm_optimizations: Option<bool>,
+}
+
+impl OptimizerConfigBuilder {
+    /// Sets [`OptimizerConfig::qgm_optimizations`].
+    pub fn qgm_optimizations(mut self, qgm_optimizations: bool) -> Self {
+        self.qgm_optimizations = Some(qgm_optimizations);
+        self
+    }
+
+    /// Builds the [`OptimizerConfig`], taking the flags that were not set from
+    /// [`OptimizerConfig::default()`].
+    pub fn build(self) -> OptimizerConfig {
+        let default = OptimizerConfig::default();
+        OptimizerConfig {
+            qgm_optimizations: self.qgm_optimizations.unwrap_or(default.qgm_optimizations),
+        }
+    }
+}
+
 /// Convert a reference to a [`StatementContext`] to an [`OptimizerConfig`].
 ///
 /// This picks up feature flag values such as `qgm_optimizations` from the `PlanContext` if this is present in
@@ -29,9 +103,7 @@ impl<'a> From<&StatementContext<'a>> for OptimizerConfig {
             Ok(pcx) => OptimizerConfig {
                 qgm_optimizations: pcx.qgm_optimizations,
             },
-            Err(..) => OptimizerConfig {
-                qgm_optimizations: false,
-            },
+            Err(..) => OptimizerConfig::default(),
         }
     }
 }
@@ -56,3 +128,109 @@ impl HirRelationExpr {
         }
     }
 }
+
+/// A cache of the results of [`HirRelationExpr::optimize_and_lower()`].
+///
+/// Lowering is fully determined by the input expression and the
+/// [`OptimizerConfig`], so its result can be reused when the same statement is
+/// planned again (e.g., a prepared statement that is executed repeatedly).
+/// Entries are keyed by the expression itself rather than by its hash, so that
+/// a hash collision can never return the wrong plan.
+///
+/// Only the lowering is cached. The subsequent MIR optimizations depend on the
+/// state of the catalog (e.g., the available indexes) and must be rerun.
+#[derive(Debug)]
+pub struct LoweringCache {
+    capacity: usize,
+    entries: HashMap<(HirRelationExpr, OptimizerConfig), expr::MirRelationExpr>,
+}
+
+impl LoweringCache {
+    /// Creates an empty cache that holds at most `capacity` lowered plans.
+    pub fn new(capacity: usize) -> LoweringCache {
+        LoweringCache {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Like [`HirRelationExpr::optimize_and_lower()`], but returns the cached
+    /// result if `expr` has previously been lowered with the same `config`.
+    ///
+    /// When the cache is full, all existing entries are evicted before the
+    /// new result is inserted.
+    pub fn optimize_and_lower(
+        &mut self,
+        expr: HirRelationExpr,
+        config: &OptimizerConfig,
+    ) -> expr::MirRelationExpr {
+        let key = (expr, config.clone());
+        if let Some(lowered) = self.entries.get(&key) {
+            return lowered.clone();
+        }
+        let lowered = key.0.clone().optimize_and_lower(config);
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.clear();
+            }
+            self.entries.insert(key, lowered.clone());
+        }
+        lowered
+    }
+
+    /// Returns the number of cached plans.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Reports whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use repr::RelationType;
+
+    use super::*;
+
+    /// Returns a constant expression with `rows` empty rows.
+    fn constant(rows: usize) -> HirRelationExpr {
+        HirRelationExpr::constant(vec![vec![]; rows], RelationType::empty())
+    }
+
+    #[test]
+    fn test_lowering_cache() {
+        let config = OptimizerConfig::default();
+        let qgm_config = OptimizerConfig::builder().qgm_optimizations(true).build();
+        let mut cache = LoweringCache::new(2);
+
+        let lowered = cache.optimize_and_lower(constant(1), &config);
+        assert_eq!(lowered, constant(1).optimize_and_lower(&config));
+        assert_eq!(cache.len(), 1);
+
+        // A hit returns the cached result without adding an entry.
+        assert_eq!(cache.optimize_and_lower(constant(1), &config), lowered);
+        assert_eq!(cache.len(), 1);
+
+        // The same expression misses with a different config.
+        cache.optimize_and_lower(constant(1), &qgm_config);
+        assert_eq!(cache.len(), 2);
+
+        // A miss in a full cache evicts all existing entries.
+        cache.optimize_and_lower(constant(2), &config);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_lowering_cache_capacity_zero() {
+        let config = OptimizerConfig::default();
+        let mut cache = LoweringCache::new(0);
+        assert_eq!(
+            cache.optimize_and_lower(constant(1), &config),
+            constant(1).optimize_and_lower(&config)
+        );
+        assert!(cache.is_empty());
+    }
+}
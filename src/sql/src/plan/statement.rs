@@ -16,7 +16,7 @@ use std::collections::BTreeMap;
 
 use anyhow::bail;
 
-use expr::GlobalId;
+use expr::{GlobalId, MirRelationExpr};
 use ore::collections::CollectionExt;
 use repr::{ColumnType, RelationDesc, ScalarType};
 
@@ -27,8 +27,9 @@ use crate::catalog::{
 use crate::names::{DatabaseSpecifier, FullName, PartialName};
 use crate::normalize;
 use crate::plan::error::PlanError;
+use crate::plan::expr::HirRelationExpr;
 use crate::plan::query;
-use crate::plan::{Params, Plan, PlanContext};
+use crate::plan::{LoweringCache, OptimizerConfig, Params, Plan, PlanContext};
 
 mod ddl;
 mod dml;
@@ -102,6 +103,7 @@ pub fn describe(
         pcx: Some(pcx),
         catalog,
         param_types: RefCell::new(param_types),
+        lowering_cache: None,
     };
 
     let desc = match stmt {
@@ -172,11 +174,15 @@ pub fn describe(
 /// The returned plan is tied to the state of the provided catalog. If the state
 /// of the catalog changes after planning, the validity of the plan is not
 /// guaranteed.
+///
+/// If `lowering_cache` is provided, the lowering of the statement's
+/// expressions is looked up in and recorded to it.
 pub fn plan(
     pcx: Option<&PlanContext>,
     catalog: &dyn SessionCatalog,
     stmt: Statement<Raw>,
     params: &Params,
+    lowering_cache: Option<&RefCell<LoweringCache>>,
 ) -> Result<Plan, anyhow::Error> {
     let param_types = params
         .types
@@ -189,6 +195,7 @@ pub fn plan(
         pcx,
         catalog,
         param_types: RefCell::new(param_types),
+        lowering_cache,
     };
 
     match stmt {
@@ -295,6 +302,8 @@ pub struct StatementContext<'a> {
     /// The types of the parameters in the query. This is filled in as planning
     /// occurs.
     pub param_types: RefCell<BTreeMap<usize, ScalarType>>,
+    /// The cache in which to look up lowered expressions, if any.
+    lowering_cache: Option<&'a RefCell<LoweringCache>>,
 }
 
 impl<'a> StatementContext<'a> {
@@ -306,6 +315,7 @@ impl<'a> StatementContext<'a> {
             pcx,
             catalog,
             param_types: Default::default(),
+            lowering_cache: None,
         }
     }
 
@@ -313,6 +323,17 @@ impl<'a> StatementContext<'a> {
         self.pcx.ok_or_else(|| anyhow::anyhow!("no plan context"))
     }
 
+    /// Like [`HirRelationExpr::optimize_and_lower()`] with the configuration
+    /// of this statement, but reuses the result from the statement's
+    /// [`LoweringCache`], if it has one.
+    pub fn optimize_and_lower(&self, expr: HirRelationExpr) -> MirRelationExpr {
+        let config = OptimizerConfig::from(self);
+        match self.lowering_cache {
+            Some(cache) => cache.borrow_mut().optimize_and_lower(expr, &config),
+            None => expr.optimize_and_lower(&config),
+        }
+    }
+
     pub fn allocate_name(&self, name: PartialName) -> FullName {
         FullName {
             database: match name.database {
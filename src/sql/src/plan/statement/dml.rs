@@ -64,7 +64,7 @@ pub fn plan_insert(
 ) -> Result<Plan, anyhow::Error> {
     let (id, mut expr) = query::plan_insert_query(scx, table_name, columns, source)?;
     expr.bind_parameters(&params)?;
-    let expr = expr.optimize_and_lower(&scx.into());
+    let expr = scx.optimize_and_lower(expr);
 
     Ok(Plan::Insert(InsertPlan { id, values: expr }))
 }
@@ -115,7 +115,7 @@ pub fn plan_read_then_write(
     }: query::ReadThenWritePlan,
 ) -> Result<Plan, anyhow::Error> {
     selection.bind_parameters(&params)?;
-    let selection = selection.optimize_and_lower(&scx.into());
+    let selection = scx.optimize_and_lower(selection);
     let mut assignments_outer = HashMap::new();
     for (idx, mut set) in assignments {
         set.bind_parameters(&params)?;
@@ -266,7 +266,7 @@ pub fn plan_query(
     } = query::plan_root_query(scx, query, lifetime)?;
     expr.bind_parameters(&params)?;
     Ok(query::PlannedQuery {
-        expr: expr.optimize_and_lower(&scx.into()),
+        expr: scx.optimize_and_lower(expr),
         desc,
         finishing,
         depends_on,
@@ -14,7 +14,7 @@
 //! pushdown can be applied across views once we understand the context
 //! in which the views will be executed.
 
-use dataflow_types::{DataflowDesc, LinearOperator, SourceConnector, SourceEnvelope};
+use dataflow_types::{DataflowDesc, LinearOperator};
 use expr::{GlobalId, Id, LocalId, MirRelationExpr, MirScalarExpr};
 use ore::id_gen::IdGen;
 use std::collections::{BTreeSet, HashMap, HashSet};
@@ -346,16 +346,7 @@ where
 
 /// Propagates information about monotonic inputs through views.
 pub fn optimize_dataflow_monotonic(dataflow: &mut DataflowDesc) -> Result<(), TransformError> {
-    let mut monotonic = std::collections::HashSet::new();
-    for (source_id, (source_desc, _)) in dataflow.source_imports.iter_mut() {
-        if let SourceConnector::External {
-            envelope: SourceEnvelope::None(_),
-            ..
-        } = source_desc.connector
-        {
-            monotonic.insert(source_id.clone());
-        }
-    }
+    let monotonic: HashSet<_> = dataflow.monotonic_ids().into_iter().collect();
 
     let monotonic_flag = MonotonicFlag::default();
 